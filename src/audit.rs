@@ -0,0 +1,427 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info};
+
+use crate::{
+    authorize::Authorize, config::AuditConfig, rule::Attached, udev::UdevEvent, usb::UsbEvent,
+};
+
+/// Something that happened which the audit trail keeps a record of
+///
+/// Actions refer to the record of whatever caused them by its `seq`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Entry {
+    /// A udev event, which rules were checked against it and which matched
+    Event {
+        #[serde(flatten)]
+        udev: Box<UdevEvent>,
+        evaluated: Vec<String>,
+        matched: Vec<String>,
+        /// Rules which matched but wait for the device's nodes before they
+        /// fire, see [`Entry::Nodes`]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        waiting: Vec<String>,
+        /// What the policy said about the device, when there is one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        policy: Option<Authorize>,
+    },
+    /// The daemon started, reloaded or is shutting down
+    Lifecycle {
+        event: UsbEvent,
+        evaluated: Vec<String>,
+        matched: Vec<String>,
+    },
+    /// A state rule's condition changed in a way that fires it
+    State {
+        rule: String,
+        active: bool,
+        devices: Vec<Attached>,
+    },
+    /// The nodes a rule was waiting for appeared, so it fired
+    Nodes { cause: u64, rule: String },
+    /// A device was authorized or deauthorized, by a rule or by the policy
+    /// when there's no rule
+    Authorize {
+        cause: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rule: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        syspath: Option<String>,
+        authorize: Authorize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// A rule's command is done, or was never run
+    Command {
+        cause: u64,
+        rule: String,
+        command: String,
+        outcome: Outcome,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<i32>,
+        /// The signal that ended the command, i.e. when it was killed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+        /// Why the command failed, i.e. the shell doesn't exist
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        duration_ms: u64,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Exited, or was killed while shutting down
+    Finished,
+    /// Killed for running past the `timeout`
+    TimedOut,
+    /// Dropped while waiting for its turn, or started while shutting down
+    Discarded,
+    /// Couldn't be started or waited on
+    Failed,
+    /// Still hadn't exited after being killed while shutting down, and was
+    /// abandoned
+    Killed,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    seq: u64,
+    ts: String,
+    #[serde(flatten)]
+    entry: &'a Entry,
+    /// Hash of the record before this one, when chaining
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev: Option<&'a str>,
+}
+
+/// An append-only trail of the events seen, the decisions made about them,
+/// and the actions taken, as JSON lines kept apart from the logs
+///
+/// Every clone writes to the same file. Without a file records are only
+/// numbered, so actions can still say what caused them.
+#[derive(Clone, Default)]
+pub struct Audit {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// `seq` of the next record
+    seq: u64,
+    writer: Option<Writer>,
+}
+
+impl Audit {
+    /// Appends to the file at `path`, carrying on with the numbering and
+    /// hash chain of the records already in it
+    pub fn open(path: &Path, config: &AuditConfig) -> anyhow::Result<Self> {
+        let (writer, seq) = Writer::open(path, config)
+            .with_context(|| format!("failed to open audit log {}", path.display()))?;
+        info!(path = ?path, "Writing audit log");
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                seq,
+                writer: Some(writer),
+            })),
+        })
+    }
+
+    /// Writes `entry`, returning the `seq` other records refer to it by
+    ///
+    /// Failing to write is logged rather than stopping the daemon.
+    pub fn record(&self, entry: Entry) -> u64 {
+        let mut inner = self.inner.lock();
+        let seq = inner.seq;
+        inner.seq += 1;
+        if let Some(writer) = &mut inner.writer {
+            if let Err(err) = writer.write(seq, &entry) {
+                error!(cause = %err, seq = %seq, "Failed to write audit record");
+            }
+        }
+        seq
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to the current file
+    size: u64,
+    /// When the current file was started
+    started: SystemTime,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+    chain: bool,
+    /// Hash of the last record written, when chaining
+    last_hash: Option<String>,
+}
+
+impl Writer {
+    fn open(path: &Path, config: &AuditConfig) -> anyhow::Result<(Self, u64)> {
+        // A file rotated away just before a restart still holds the last
+        // record
+        let mut last = last_line(path)?;
+        if last.is_none() {
+            last = last_line(&rotated(path, 1))?;
+        }
+        let (seq, last_hash) = match last {
+            Some(line) => {
+                let value: serde_json::Value =
+                    serde_json::from_str(&line).context("last record is invalid")?;
+                let seq = value["seq"].as_u64().context("last record has no 'seq'")?;
+                (seq + 1, value["hash"].as_str().map(String::from))
+            }
+            None => (0, None),
+        };
+
+        let file = open_append(path)?;
+        let meta = file.metadata()?;
+        let started = match meta.len() {
+            0 => SystemTime::now(),
+            _ => meta.created().or_else(|_| meta.modified())?,
+        };
+        let writer = Self {
+            path: path.into(),
+            file,
+            size: meta.len(),
+            started,
+            max_size: config.max_size,
+            max_age: config.max_age(),
+            keep: config.keep(),
+            chain: config.hash_chain,
+            last_hash,
+        };
+        Ok((writer, seq))
+    }
+
+    fn write(&mut self, seq: u64, entry: &Entry) -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        if self.due(now) {
+            self.rotate(now)?;
+        }
+
+        let record = Record {
+            seq,
+            ts: timestamp(now),
+            entry,
+            prev: self.last_hash.as_deref().filter(|_| self.chain),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        if self.chain {
+            // The hash covers the line as it would be without it, so checking
+            // it only means taking it back off
+            let hash = sha256(&line);
+            line.pop();
+            line.push_str(&format!(",\"hash\":\"{hash}\"}}"));
+            self.last_hash = Some(hash);
+        }
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the current file should be rotated before writing to it again
+    fn due(&self, now: SystemTime) -> bool {
+        let age = now.duration_since(self.started).unwrap_or_default();
+        self.size > 0
+            && (self.max_size.is_some_and(|max| self.size >= max)
+                || self.max_age.is_some_and(|max| age >= max))
+    }
+
+    /// Moves the current file to PATH.1, and any older ones along, dropping
+    /// the oldest
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        debug!(path = ?self.path, "Rotating audit log");
+        for n in (1..self.keep).rev() {
+            let from = rotated(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.started = now;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// The `n`th newest rotated file of the audit log at `path`
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+fn last_line(path: &Path) -> io::Result<Option<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    Ok(last)
+}
+
+fn sha256(buf: &str) -> String { format!("{:x}", Sha256::digest(buf.as_bytes())) }
+
+/// Checks the hash chain through the audit logs at `paths`, oldest first,
+/// returning how many records it covers
+///
+/// The first record may follow one that's been rotated away, but every
+/// record after it has to follow the one before.
+pub fn verify(paths: &[PathBuf]) -> anyhow::Result<usize> {
+    let mut prev: Option<String> = None;
+    let mut count = 0;
+    for path in paths {
+        let file =
+            File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let at = format!("{}:{}", path.display(), i + 1);
+            let value: serde_json::Value =
+                serde_json::from_str(&line).with_context(|| format!("{at}: invalid record"))?;
+            let Some((unhashed, hash)) = split_hash(&line) else {
+                bail!("{at}: record has no hash");
+            };
+            if sha256(&unhashed) != hash {
+                bail!("{at}: record doesn't match its hash");
+            }
+            if count > 0 && value["prev"].as_str() != prev.as_deref() {
+                bail!("{at}: record doesn't follow the one before it");
+            }
+            prev = Some(hash.into());
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Takes the trailing `"hash"` back off of a record, returning the line that
+/// was hashed and the hash
+fn split_hash(line: &str) -> Option<(String, &str)> {
+    const KEY: &str = ",\"hash\":\"";
+    let at = line.rfind(KEY)?;
+    let hash = line[at + KEY.len()..].strip_suffix("\"}")?;
+    Some((format!("{}}}", &line[..at]), hash))
+}
+
+/// `t` as an RFC 3339 timestamp in UTC, to the millisecond
+fn timestamp(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, time) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Days since the epoch to a civil date, from Howard Hinnant's
+    // `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn lifecycle(event: UsbEvent) -> Entry {
+        Entry::Lifecycle {
+            event,
+            evaluated: vec!["startup".into()],
+            matched: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn audit_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap = UNIX_EPOCH + Duration::from_millis(951_782_400_250);
+        assert_eq!(timestamp(leap), "2000-02-29T00:00:00.250Z");
+        let later = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(timestamp(later), "2023-11-14T22:13:20.000Z");
+    }
+
+    #[test]
+    fn audit_chain_and_rotation() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.log");
+        let config = AuditConfig {
+            max_size: Some(1),
+            keep: Some(2),
+            hash_chain: true,
+            ..Default::default()
+        };
+
+        let audit = Audit::open(&path, &config).unwrap();
+        assert_eq!(audit.record(lifecycle(UsbEvent::Startup)), 0);
+        assert_eq!(audit.record(lifecycle(UsbEvent::Reload)), 1);
+        drop(audit);
+
+        // Numbering and the chain carry on after a restart, and every record
+        // past the first rotates the file
+        let audit = Audit::open(&path, &config).unwrap();
+        assert_eq!(audit.record(lifecycle(UsbEvent::Shutdown)), 2);
+        let files = [rotated(&path, 2), rotated(&path, 1), path.clone()];
+        assert_eq!(verify(&files).unwrap(), 3);
+        assert!(!rotated(&path, 3).exists());
+
+        let line = fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["kind"], "lifecycle");
+        assert_eq!(record["event"], "shutdown");
+        assert!(record["ts"].as_str().unwrap().ends_with('Z'));
+
+        // Editing a record, or dropping one, breaks the chain
+        fs::write(&path, line.replace("shutdown", "reload")).unwrap();
+        assert!(verify(&files).is_err());
+        fs::write(&path, line).unwrap();
+        assert!(verify(&[rotated(&path, 2), path.clone()]).is_err());
+    }
+}
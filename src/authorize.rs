@@ -0,0 +1,132 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+use tracing::debug;
+
+use crate::usb::UsbPort;
+
+/// Whether a device may be used, written to its sysfs `authorized` file
+///
+/// A deauthorized device stays plugged in and keeps its descriptors, but the
+/// kernel drops its interfaces so no driver can use it.
+#[derive(Serialize, EnumString, Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Authorize {
+    Allow,
+    Deny,
+}
+
+impl Authorize {
+    fn value(self) -> &'static str {
+        match self {
+            Authorize::Allow => "1",
+            Authorize::Deny => "0",
+        }
+    }
+}
+
+/// What happens to devices no rule authorizes or deauthorizes
+#[derive(Deserialize, Display, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Policy {
+    /// Leave them the way the kernel set them up, which is usually allowed
+    #[default]
+    AllowAll,
+    /// New devices start out deauthorized, and only those in the allowlist
+    /// are authorized
+    DefaultDeny,
+}
+
+/// The sysfs tree authorization is written to, normally mounted at `/sys`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Sysfs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self { Self { root: root.into() } }
+
+    /// Authorizes or deauthorizes the device plugged into `port`
+    pub fn authorize(&self, port: &UsbPort, authorize: Authorize) -> io::Result<()> {
+        let Some(syspath) = port.syspath() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "port has no syspath",
+            ));
+        };
+        // Deauthorizing a root hub would cut off everything on its bus
+        if port.is_root_hub() {
+            debug!(port = %port, "Leaving root hub authorized");
+            return Ok(());
+        }
+        debug!(syspath = %syspath, %authorize, "Writing authorization");
+        fs::write(self.path(syspath).join("authorized"), authorize.value())
+    }
+
+    /// Sets whether devices plugged in from now on start out authorized, on
+    /// every root hub (i.e. `usb1`)
+    pub fn set_default(&self, authorize: Authorize) -> io::Result<()> {
+        let buses = self.root.join("bus/usb/devices");
+        for entry in fs::read_dir(&buses)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with("usb") {
+                continue;
+            }
+            let path = entry.path().join("authorized_default");
+            debug!(path = ?path, %authorize, "Setting default authorization");
+            fs::write(&path, authorize.value())?;
+        }
+        Ok(())
+    }
+
+    /// Where the absolute `syspath` udev reported is under this root
+    fn path(&self, syspath: &str) -> PathBuf {
+        let relative = Path::new(syspath)
+            .strip_prefix("/sys")
+            .or_else(|_| Path::new(syspath).strip_prefix("/"))
+            .unwrap_or(Path::new(syspath));
+        self.root.join(relative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn sysfs_authorize() {
+        let root = TempDir::new("sysfs");
+        let device = root.join("devices/pci0000:00/0000:00:14.0/usb1/1-2");
+        let hub = root.join("devices/pci0000:00/0000:00:14.0/usb1");
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(root.join("bus/usb/devices/usb1")).unwrap();
+        fs::create_dir_all(root.join("bus/usb/devices/1-2")).unwrap();
+        let sysfs = Sysfs::new(&*root);
+
+        let mut port = UsbPort::new("front");
+        port.set_property("syspath", "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2");
+        port.set_property("sysname", "1-2");
+        sysfs.authorize(&port, Authorize::Deny).unwrap();
+        assert_eq!(fs::read_to_string(device.join("authorized")).unwrap(), "0");
+        sysfs.authorize(&port, Authorize::Allow).unwrap();
+        assert_eq!(fs::read_to_string(device.join("authorized")).unwrap(), "1");
+
+        let mut root_hub = UsbPort::new("bus");
+        root_hub.set_property("syspath", "/sys/devices/pci0000:00/0000:00:14.0/usb1");
+        root_hub.set_property("sysname", "usb1");
+        sysfs.authorize(&root_hub, Authorize::Deny).unwrap();
+        assert!(!hub.join("authorized").exists());
+
+        sysfs.set_default(Authorize::Deny).unwrap();
+        let default = root.join("bus/usb/devices/usb1/authorized_default");
+        assert_eq!(fs::read_to_string(default).unwrap(), "0");
+        assert!(!root.join("bus/usb/devices/1-2/authorized_default").exists());
+    }
+}
@@ -1,16 +1,23 @@
 mod check;
+mod ctl;
+mod diff;
+mod enroll;
+mod label_ports;
 mod listen;
 mod rule;
 mod run;
 mod scan;
+mod verify_audit;
 
 use std::env;
 
-use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
 use crate::{
     ctx::Ctx,
+    listener::UdevFilter,
     printer::{ColorChoice, OutFormat, Printer},
+    usb::{UsbDevice, UsbInventory, UsbPort},
 };
 
 #[enum_delegate::register]
@@ -101,6 +108,11 @@ pub enum UsbWatchCmd {
     Check(check::UsbWatchCheck),
     Scan(scan::UsbWatchScan),
     CreateRule(rule::UsbWatchCreateRule),
+    Ctl(ctl::UsbWatchCtl),
+    LabelPorts(label_ports::UsbWatchLabelPorts),
+    Enroll(enroll::UsbWatchEnroll),
+    Diff(diff::UsbWatchDiff),
+    VerifyAudit(verify_audit::UsbWatchVerifyAudit),
 }
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq)]
@@ -109,3 +121,50 @@ pub enum ForObject {
     Devices,
     All,
 }
+
+impl ForObject {
+    pub fn ports(self) -> bool { self == ForObject::Ports || self == ForObject::All }
+
+    pub fn devices(self) -> bool { self == ForObject::Devices || self == ForObject::All }
+
+    /// Keeps only the KIND of objects asked for
+    pub fn inventory(self, ports: Vec<UsbPort>, devices: Vec<UsbDevice>) -> UsbInventory {
+        UsbInventory {
+            ports: self.ports().then_some(ports),
+            devices: self.devices().then_some(devices),
+            ..Default::default()
+        }
+    }
+}
+
+/// Which udev devices to listen for
+#[derive(Args, Clone, Debug, Default)]
+pub struct FilterArgs {
+    /// Monitor udev SUBSYSTEM instead of 'usb' (may be repeated)
+    #[arg(long, value_name = "SUBSYSTEM")]
+    pub subsystem: Vec<String>,
+
+    /// Only handle devices of DEVTYPE instead of 'usb_device' (may be repeated)
+    ///
+    /// Use '*' to handle every devtype, including 'usb_interface'
+    #[arg(long, value_name = "DEVTYPE")]
+    pub devtype: Vec<String>,
+}
+
+impl FilterArgs {
+    /// Overrides the parts of `filter` given on the command line
+    pub fn apply(&self, mut filter: UdevFilter) -> UdevFilter {
+        if !self.subsystem.is_empty() {
+            filter.subsystems.clone_from(&self.subsystem);
+        }
+        if !self.devtype.is_empty() {
+            filter.devtypes = self
+                .devtype
+                .iter()
+                .filter(|dt| *dt != "*")
+                .cloned()
+                .collect();
+        }
+        filter
+    }
+}
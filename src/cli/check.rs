@@ -1,25 +1,25 @@
-use std::{
-    fs::{self, File},
-    path::PathBuf,
-};
+use std::path::PathBuf;
 
 use clap::Args;
 use tracing::warn;
-use yaml_rust::YamlLoader;
 
 use crate::{
     cli::Cmd,
     ctx::Ctx,
+    printer::print_doc,
     rule::Rules,
-    usb::{UsbDevices, UsbPorts},
+    secure,
+    usb::{UsbDevices, UsbInventory, UsbPorts},
 };
 
 /// List matched components from loaded rules
 #[derive(Args, Debug)]
 pub struct UsbWatchCheck {
-    /// Rules file to use
-    #[arg(long, short)]
-    pub rules: Option<PathBuf>,
+    /// Rules file to use, or a directory of `*.yml` rules files
+    ///
+    /// May be given more than once, just like for `usbwatch run`.
+    #[arg(long, short, value_name = "PATH")]
+    pub rules: Vec<PathBuf>,
 
     /// Devices to match against
     #[arg(long, short)]
@@ -31,25 +31,37 @@ pub struct UsbWatchCheck {
 }
 
 impl Cmd for UsbWatchCheck {
-    fn run(&self, _ctx: &mut Ctx) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
         warn!("Not fully implemented");
 
         if let Some(path) = &self.devices {
-            let file = File::open(path).unwrap();
-            let devices: UsbDevices = serde_yaml::from_reader(file).unwrap();
-            cli_println!("{:#?}", devices);
+            let devices = UsbDevices {
+                devices: UsbInventory::from_path(path)?.devices(),
+            };
+            print_doc(&devices, ctx.format)?;
         }
 
         if let Some(path) = &self.ports {
-            let file = File::open(path).unwrap();
-            let ports: UsbPorts = serde_yaml::from_reader(file).unwrap();
-            cli_println!("{:#?}", ports);
+            let ports = UsbPorts {
+                ports: UsbInventory::from_path(path)?.ports(),
+            };
+            print_doc(&ports, ctx.format)?;
+        }
+
+        let mut files: Vec<PathBuf> = self.devices.iter().chain(&self.ports).cloned().collect();
+        if !self.rules.is_empty() {
+            let rules = Rules::from_paths(&self.rules)?;
+            print_doc(&rules, ctx.format)?;
+            files.extend(secure::rules_paths(&self.rules, &rules.rules));
         }
 
-        if let Some(path) = &self.rules {
-            let buf = fs::read_to_string(path).unwrap();
-            let rules = Rules::from(&YamlLoader::load_from_str(&buf).unwrap()[0]);
-            cli_println!("{:#?}", rules);
+        // Only a warning here, since the files are often checked before
+        // they're installed
+        for problem in secure::check_all(&files, 0) {
+            cli_eprint!(@Yellow, "warn: ");
+            cli_eprintln!(
+                "{problem}; `usbwatch run` refuses to load it as root without --insecure-allow"
+            );
         }
         Ok(())
     }
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Args, Subcommand};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    signal::unix::{signal, SignalKind},
+};
+
+use crate::{
+    cli::Cmd,
+    config::Config,
+    control::{Notification, Request, Subscription, DEFAULT_SOCKET},
+    ctx::Ctx,
+    printer::print_doc,
+    usb::UsbEvent,
+};
+
+/// Talk to a running `usbwatch run` daemon
+#[derive(Args, Debug)]
+pub struct UsbWatchCtl {
+    /// Control socket of the daemon [default: /run/usbwatch.sock]
+    ///
+    /// Falls back to the socket in /etc/usbwatch/config.yml when there is one.
+    #[arg(long, value_name = "PATH", env = "USBWATCH_SOCKET", global = true)]
+    pub socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub cmd: UsbWatchCtlCmd,
+}
+
+impl Cmd for UsbWatchCtl {
+    fn update_ctx(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        ctx.socket = match &self.socket {
+            Some(socket) => Some(socket.clone()),
+            None => Config::load(None)?.run.socket,
+        };
+        Ok(())
+    }
+
+    fn next_cmd(&self) -> Option<&dyn Cmd> { Some(&self.cmd) }
+}
+
+#[enum_delegate::implement(Cmd)]
+#[derive(Subcommand, Debug)]
+pub enum UsbWatchCtlCmd {
+    Subscribe(UsbWatchCtlSubscribe),
+}
+
+/// Stream events and rule firings from the daemon as they happen
+#[derive(Args, Clone, Debug)]
+pub struct UsbWatchCtlSubscribe {
+    /// Only display firings of the rule NAME
+    #[arg(long, short, value_name = "NAME")]
+    pub rule: Option<String>,
+
+    /// Only display KIND of events
+    #[arg(long, short, value_enum, value_name = "KIND", default_value = "all")]
+    pub event: UsbEvent,
+}
+
+impl Cmd for UsbWatchCtlSubscribe {
+    fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        let socket = ctx.socket.clone().unwrap_or_else(|| DEFAULT_SOCKET.into());
+        let req = Request::Subscribe(Subscription {
+            rule: self.rule.clone(),
+            event: self.event,
+        });
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut sigint = signal(SignalKind::interrupt())?;
+
+                let mut stream = UnixStream::connect(&socket)
+                    .await
+                    .with_context(|| format!("failed to connect to {}", socket.display()))?;
+                let mut buf = serde_json::to_vec(&req)?;
+                buf.push(b'\n');
+                stream.write_all(&buf).await?;
+
+                let mut lines = BufReader::new(stream).lines();
+                loop {
+                    let line = tokio::select! {
+                        res = lines.next_line() => res?,
+                        _ = sigint.recv() => return Ok(()),
+                    };
+                    let Some(line) = line else {
+                        cli_eprintln!("daemon closed the connection");
+                        return Ok(());
+                    };
+                    let notification: Notification = serde_json::from_str(&line)?;
+                    print_doc(&notification, ctx.format)?;
+                }
+            })
+    }
+}
@@ -0,0 +1,257 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Args;
+use serde::Serialize;
+
+use crate::{
+    cli::{scan::attached, Cmd},
+    ctx::Ctx,
+    printer::print_doc,
+    usb::{UsbDevice, UsbInventory, UsbPort},
+};
+
+/// Compare the attached devices against a baseline inventory
+///
+/// Exits non-zero if anything differs, so it can be used as a compliance
+/// check.
+#[derive(Args, Debug)]
+pub struct UsbWatchDiff {
+    /// Devices expected to be attached
+    ///
+    /// A device with a `port` key is expected in the port of that name from
+    /// --ports.
+    #[arg(long, short, value_name = "PATH")]
+    pub devices: PathBuf,
+
+    /// Named ports the devices' `port` keys refer to
+    #[arg(long, short, value_name = "PATH")]
+    pub ports: Option<PathBuf>,
+}
+
+impl Cmd for UsbWatchDiff {
+    fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        let expected = UsbInventory::from_path(&self.devices)?.devices();
+        let ports = match &self.ports {
+            Some(path) => UsbInventory::from_path(path)?.ports(),
+            None => Vec::new(),
+        };
+
+        let report = diff(&expected, &ports, attached()?)?;
+        print_doc(&report, ctx.format)?;
+        if !report.is_empty() {
+            bail!(
+                "attached devices differ from {} in {} way(s)",
+                self.devices.display(),
+                report.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// How the attached devices differ from those expected
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    /// Names of expected devices which aren't attached
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    /// Attached devices which weren't expected
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unexpected: Vec<Unexpected>,
+    /// Expected devices attached to some other port
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub wrong_port: Vec<WrongPort>,
+}
+
+impl Report {
+    pub fn len(&self) -> usize {
+        self.missing.len() + self.unexpected.len() + self.wrong_port.len()
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Unexpected {
+    pub port: String,
+    pub device: UsbDevice,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WrongPort {
+    pub name: String,
+    pub expected: String,
+    pub port: String,
+}
+
+/// Compares `attached` devices against those `expected`, using the same
+/// matching as rules do
+///
+/// Each attached device satisfies at most one expected device, so two
+/// expected devices with the same definition need two attached. Root hubs
+/// are part of the machine rather than something plugged in, and are never
+/// unexpected.
+pub fn diff(
+    expected: &[UsbDevice],
+    ports: &[UsbPort],
+    attached: Vec<(UsbPort, UsbDevice)>,
+) -> anyhow::Result<Report> {
+    let mut attached: Vec<_> = attached
+        .into_iter()
+        .filter(|(port, device)| !device.is_empty() && !port.is_root_hub())
+        .map(|(port, mut device)| {
+            device.strip_volatile();
+            (port, device)
+        })
+        .map(Some)
+        .collect();
+
+    // Devices expected in a particular port go first so one that could be
+    // anywhere doesn't take their place
+    let (placed, anywhere): (Vec<_>, Vec<_>) = expected.iter().partition(|d| d.port.is_some());
+
+    let mut report = Report::default();
+    for device in placed.into_iter().chain(anywhere) {
+        let name = device.name.clone().unwrap_or_default();
+        let want_port = match &device.port {
+            Some(port_name) => match ports.iter().find(|p| p.name.as_ref() == Some(port_name)) {
+                Some(port) => Some((port_name, port)),
+                None => bail!("device '{name}' expects port '{port_name}' which isn't defined"),
+            },
+            None => None,
+        };
+
+        let candidates: Vec<usize> = attached
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.as_ref().is_some_and(|(_, d)| d == device))
+            .map(|(i, _)| i)
+            .collect();
+        // Prefer a match that's where it belongs
+        let found = candidates
+            .iter()
+            .find(|&&i| {
+                want_port.map_or(true, |(_, want)| {
+                    attached[i].as_ref().is_some_and(|(p, _)| p == want)
+                })
+            })
+            .or(candidates.first());
+
+        let Some((port, _)) = found.and_then(|&i| attached[i].take()) else {
+            report.missing.push(name);
+            continue;
+        };
+        if let Some((port_name, want)) = want_port {
+            if &port != want {
+                report.wrong_port.push(WrongPort {
+                    name,
+                    expected: port_name.clone(),
+                    port: port_label(&port, ports),
+                });
+            }
+        }
+    }
+
+    report.unexpected = attached
+        .into_iter()
+        .flatten()
+        .map(|(port, device)| Unexpected {
+            port: port_label(&port, ports),
+            device,
+        })
+        .collect();
+
+    Ok(report)
+}
+
+/// The name `port` is known by, falling back to its sysname
+fn port_label(port: &UsbPort, ports: &[UsbPort]) -> String {
+    ports
+        .iter()
+        .find(|p| *p == port)
+        .and_then(|p| p.name.clone())
+        .or_else(|| port.sysname().map(Into::into))
+        .unwrap_or_else(|| "unknown".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(sysname: &str) -> UsbPort {
+        let mut port = UsbPort::default();
+        port.set_property("sysname", sysname);
+        port
+    }
+
+    fn named_port(name: &str, sysname: &str) -> UsbPort {
+        let mut port = port(sysname);
+        port.name = Some(name.into());
+        port
+    }
+
+    fn key(serial: &str) -> UsbDevice {
+        let mut device = UsbDevice::default();
+        device.set_property("ID_VENDOR_ID", "1050");
+        device.set_property("ID_SERIAL_SHORT", serial);
+        device
+    }
+
+    fn expect(name: &str, serial: &str, port: Option<&str>) -> UsbDevice {
+        let mut device = key(serial).keep(name, &["ID_VENDOR_ID", "ID_SERIAL_SHORT"]);
+        device.port = port.map(Into::into);
+        device
+    }
+
+    #[test]
+    fn diff_matches() {
+        let ports = [named_port("Left", "2-1"), named_port("Right", "2-2")];
+        let expected = [
+            expect("Alice", "123", Some("Left")),
+            expect("Bob", "456", None),
+        ];
+        let attached = vec![
+            (port("usb2"), key("root")),
+            (port("2-2"), key("456")),
+            (port("2-1"), key("123")),
+        ];
+
+        assert!(diff(&expected, &ports, attached).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_differences() {
+        let ports = [named_port("Left", "2-1"), named_port("Right", "2-2")];
+        let expected = [
+            expect("Alice", "123", Some("Left")),
+            expect("Bob", "456", None),
+        ];
+        let attached = vec![(port("2-2"), key("123")), (port("2-3"), key("789"))];
+
+        let report = diff(&expected, &ports, attached).unwrap();
+        assert_eq!(report.len(), 3);
+        assert_eq!(report.missing, ["Bob"]);
+        assert_eq!(report.wrong_port[0].name, "Alice");
+        assert_eq!(report.wrong_port[0].port, "Right");
+        assert_eq!(report.unexpected[0].port, "2-3");
+        assert_eq!(report.unexpected[0].device.serial(), Some("789"));
+    }
+
+    #[test]
+    fn diff_twins() {
+        // Two identical definitions need two devices, and the one in the right
+        // port is the one that counts
+        let ports = [named_port("Left", "2-1")];
+        let expected = [
+            expect("Any Key", "123", None),
+            expect("Left Key", "123", Some("Left")),
+        ];
+        let attached = vec![(port("2-1"), key("123")), (port("2-2"), key("123"))];
+
+        assert!(diff(&expected, &ports, attached).unwrap().is_empty());
+
+        assert!(diff(&[expect("Alice", "123", Some("Nowhere"))], &ports, vec![]).is_err());
+    }
+}
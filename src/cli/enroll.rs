@@ -0,0 +1,280 @@
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use anyhow::bail;
+use clap::Args;
+
+use crate::{
+    cli::Cmd,
+    ctx::Ctx,
+    listener::UdevFilter,
+    prompt::Prompt,
+    source::EventSource,
+    usb::{save_yaml, UsbDevice, UsbDevices, UsbEvent, UsbInventory},
+};
+
+/// Fields kept when none are chosen; enough to tell apart two of the same
+/// model
+const DEFAULT_FIELDS: &[&str] = &["ID_VENDOR_ID", "ID_MODEL_ID", "ID_SERIAL_SHORT"];
+
+/// Add the next device plugged in to a devices inventory
+#[derive(Args, Debug)]
+pub struct UsbWatchEnroll {
+    /// Devices inventory to add to, created if it doesn't exist
+    #[arg(long, short, value_name = "PATH")]
+    pub devices: PathBuf,
+
+    /// Name for the device in the inventory
+    #[arg(long, short, value_name = "NAME")]
+    pub name: String,
+
+    /// Keep the field KEY instead of asking which to keep (may be repeated)
+    #[arg(long, short, value_name = "KEY")]
+    pub keep: Vec<String>,
+
+    /// Read events from PATH instead of udev
+    #[arg(long, value_name = "PATH", hide = true)]
+    pub replay: Option<PathBuf>,
+}
+
+impl Cmd for UsbWatchEnroll {
+    fn run(&self, _ctx: &mut Ctx) -> anyhow::Result<()> {
+        let mut devices = if self.devices.exists() {
+            UsbInventory::from_path(&self.devices)?.devices()
+        } else {
+            Vec::new()
+        };
+
+        let device = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut source = match &self.replay {
+                    Some(path) => EventSource::from_path(path)?,
+                    None => EventSource::udev(UdevFilter {
+                        events: vec![UsbEvent::Add],
+                        ..Default::default()
+                    })?,
+                };
+                let keep = (!self.keep.is_empty()).then_some(&*self.keep);
+                enroll(
+                    &mut source,
+                    &mut Prompt::stdio(),
+                    &devices,
+                    &self.name,
+                    keep,
+                )
+                .await
+            })?;
+
+        let Some(device) = device else {
+            cli_println!("Nothing enrolled");
+            return Ok(());
+        };
+        devices.push(device);
+        save_yaml(&self.devices, &UsbDevices { devices })?;
+        cli_println!("Enrolled '{}' in {}", self.name, self.devices.display());
+
+        Ok(())
+    }
+}
+
+/// Captures the next device plugged in as `name`, keeping only the fields in
+/// `keep`, or those the user picks when `keep` is `None`
+///
+/// Returns `None` if the user decides not to enroll the device after all,
+/// such as when it would also match a device already in `existing`.
+pub async fn enroll<R: BufRead, W: Write>(
+    source: &mut EventSource,
+    prompt: &mut Prompt<R, W>,
+    existing: &[UsbDevice],
+    name: &str,
+    keep: Option<&[String]>,
+) -> anyhow::Result<Option<UsbDevice>> {
+    if existing.iter().any(|d| d.name.as_deref() == Some(name)) {
+        bail!("a device named '{name}' is already enrolled");
+    }
+
+    prompt.say(&format!("Plug in '{name}'..."))?;
+    let Some(event) = source.next_add().await else {
+        bail!("no device was plugged in");
+    };
+    let captured = event.device;
+    let fields = captured.fields();
+    if fields.is_empty() {
+        bail!("the device plugged in has no identifying fields");
+    }
+
+    let keys: Vec<&str> = match keep {
+        Some(keep) => {
+            for key in keep {
+                if !fields.iter().any(|(k, _)| k == key) {
+                    bail!("the device plugged in has no field '{key}'");
+                }
+            }
+            keep.iter().map(String::as_str).collect()
+        }
+        None => match choose_fields(prompt, &fields)? {
+            Some(keys) => keys,
+            None => return Ok(None),
+        },
+    };
+    let device = captured.keep(name, &keys);
+
+    let conflicts: Vec<&str> = existing
+        .iter()
+        .filter(|d| *d == &device)
+        .map(|d| d.name.as_deref().unwrap_or("<unnamed>"))
+        .collect();
+    if !conflicts.is_empty() {
+        prompt.say(&format!(
+            "'{name}' would also match already enrolled: {}",
+            conflicts.join(", ")
+        ))?;
+        prompt.say("Keep more fields to tell them apart.")?;
+        if !prompt.confirm("Enroll anyway?", false)? {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(device))
+}
+
+/// Asks which of `fields` to keep, or `None` at end of input
+fn choose_fields<'a, R: BufRead, W: Write>(
+    prompt: &mut Prompt<R, W>,
+    fields: &'a [(String, String)],
+) -> anyhow::Result<Option<Vec<&'a str>>> {
+    let defaults: Vec<&str> = fields
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .filter(|k| DEFAULT_FIELDS.contains(k))
+        .collect();
+
+    prompt.say("Captured:")?;
+    for (i, (key, value)) in fields.iter().enumerate() {
+        let mark = if defaults.contains(&key.as_str()) {
+            "*"
+        } else {
+            " "
+        };
+        prompt.say(&format!("  {mark} {:>2}) {key}: {value}", i + 1))?;
+    }
+
+    loop {
+        let Some(answer) = prompt.ask(
+            "Fields to keep, as numbers or keys separated by commas (empty keeps those marked *): ",
+        )?
+        else {
+            return Ok(None);
+        };
+        if answer.is_empty() && !defaults.is_empty() {
+            return Ok(Some(defaults));
+        }
+
+        let mut keys = Vec::new();
+        let mut bad = None;
+        for choice in answer.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let found = match choice.parse::<usize>() {
+                Ok(n) => fields.get(n.wrapping_sub(1)),
+                Err(_) => fields.iter().find(|(k, _)| k == choice),
+            };
+            match found {
+                Some((k, _)) => keys.push(k.as_str()),
+                None => bad = Some(choice),
+            }
+        }
+        match bad {
+            Some(choice) => prompt.say(&format!("'{choice}' is not one of the fields"))?,
+            None if keys.is_empty() => prompt.say("Choose at least one field")?,
+            None => return Ok(Some(keys)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{udev::UdevEvent, usb::UsbPort};
+
+    fn yubikey(serial: &str) -> UdevEvent {
+        let mut device = UsbDevice::default();
+        device.set_property("ID_VENDOR_ID", "1050");
+        device.set_property("ID_MODEL_ID", "0407");
+        device.set_property("ID_SERIAL_SHORT", serial);
+        device.set_property("ID_VENDOR", "Yubico");
+        UdevEvent {
+            event_kind: UsbEvent::Add,
+            device,
+            port: UsbPort::default(),
+            nodes: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn enroll_default_fields() {
+        let mut source = EventSource::replay([yubikey("123")]);
+        let mut out = Vec::new();
+        let mut prompt = Prompt::new(&b"9\n\n"[..], &mut out);
+
+        let device = enroll(&mut source, &mut prompt, &[], "Alice Yubikey", None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(device.name.as_deref(), Some("Alice Yubikey"));
+        assert_eq!(device.serial(), Some("123"));
+        assert_eq!(device.vendor(), None);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("'9' is not one of the fields"));
+    }
+
+    #[tokio::test]
+    async fn enroll_conflict() {
+        let alice = yubikey("123").device.keep("Alice Yubikey", DEFAULT_FIELDS);
+        let keep = ["ID_VENDOR_ID".to_string()];
+
+        // Without the serial Bob's key can't be told apart from Alice's
+        let mut source = EventSource::replay([yubikey("456")]);
+        let mut out = Vec::new();
+        let mut prompt = Prompt::new(&b"\n"[..], &mut out);
+        let device = enroll(
+            &mut source,
+            &mut prompt,
+            std::slice::from_ref(&alice),
+            "Bob Yubikey",
+            Some(&keep),
+        )
+        .await
+        .unwrap();
+        assert!(device.is_none());
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("would also match already enrolled: Alice Yubikey"));
+
+        // With it there's no conflict
+        let mut source = EventSource::replay([yubikey("456")]);
+        let mut prompt = Prompt::new(&b"\n"[..], Vec::new());
+        let device = enroll(&mut source, &mut prompt, &[alice], "Bob Yubikey", None)
+            .await
+            .unwrap();
+        assert_eq!(device.unwrap().serial(), Some("456"));
+    }
+
+    #[tokio::test]
+    async fn enroll_name_taken() {
+        let alice = yubikey("123").device.keep("Alice Yubikey", DEFAULT_FIELDS);
+        let mut source = EventSource::replay([yubikey("456")]);
+        let mut prompt = Prompt::new(&b""[..], Vec::new());
+
+        assert!(
+            enroll(&mut source, &mut prompt, &[alice], "Alice Yubikey", None)
+                .await
+                .is_err()
+        );
+    }
+}
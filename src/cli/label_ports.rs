@@ -0,0 +1,184 @@
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use clap::Args;
+
+use crate::{
+    cli::Cmd,
+    ctx::Ctx,
+    listener::UdevFilter,
+    prompt::Prompt,
+    source::EventSource,
+    usb::{save_yaml, UsbEvent, UsbInventory, UsbPort, UsbPorts},
+};
+
+/// Name ports by plugging a device into each one
+///
+/// Ports are saved to the file after each one is named, so it's safe to stop
+/// with Ctrl-C at any point.
+#[derive(Args, Debug)]
+pub struct UsbWatchLabelPorts {
+    /// Ports inventory to write, adding to it if it already exists
+    #[arg(long, short, value_name = "PATH")]
+    pub ports: PathBuf,
+
+    /// Read events from PATH instead of udev
+    #[arg(long, value_name = "PATH", hide = true)]
+    pub replay: Option<PathBuf>,
+}
+
+impl Cmd for UsbWatchLabelPorts {
+    fn run(&self, _ctx: &mut Ctx) -> anyhow::Result<()> {
+        let mut ports = if self.ports.exists() {
+            UsbInventory::from_path(&self.ports)?.ports()
+        } else {
+            Vec::new()
+        };
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut source = match &self.replay {
+                    Some(path) => EventSource::from_path(path)?,
+                    None => EventSource::udev(UdevFilter {
+                        events: vec![UsbEvent::Add],
+                        ..Default::default()
+                    })?,
+                };
+                let mut prompt = Prompt::stdio();
+
+                let named = label_ports(&mut source, &mut prompt, &mut ports, |ports| {
+                    save_yaml(
+                        &self.ports,
+                        &UsbPorts {
+                            ports: ports.to_vec(),
+                        },
+                    )
+                })
+                .await?;
+                cli_println!("Named {named} ports in {}", self.ports.display());
+                Ok(())
+            })
+    }
+}
+
+/// Asks for a name for each port a device is plugged into, calling `save`
+/// with all the ports after each one is named
+///
+/// A port that's already in `ports` is renamed rather than added again.
+/// Returns how many ports were named.
+pub async fn label_ports<R, W, F>(
+    source: &mut EventSource,
+    prompt: &mut Prompt<R, W>,
+    ports: &mut Vec<UsbPort>,
+    mut save: F,
+) -> anyhow::Result<usize>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&[UsbPort]) -> anyhow::Result<()>,
+{
+    let mut named = 0;
+    loop {
+        prompt.say("Plug a device into the port you want to name...")?;
+        let Some(event) = source.next_add().await else {
+            return Ok(named);
+        };
+        let mut port = event.port;
+        port.strip_volatile();
+
+        let existing = ports.iter().position(|p| p == &port);
+        let location = port.sysname().unwrap_or("unknown").to_string();
+        let question = match existing.and_then(|i| ports[i].name.as_deref()) {
+            Some(name) if !name.is_empty() => {
+                format!("Port {location} is already named '{name}'; new name (empty keeps it): ")
+            }
+            _ => format!("Name for port {location} (empty skips it): "),
+        };
+
+        let name = loop {
+            let Some(name) = prompt.ask(&question)? else {
+                return Ok(named);
+            };
+            let taken = ports
+                .iter()
+                .enumerate()
+                .any(|(i, p)| Some(i) != existing && p.name.as_deref() == Some(&*name));
+            if !name.is_empty() && taken {
+                prompt.say(&format!("'{name}' is already the name of another port"))?;
+                continue;
+            }
+            break name;
+        };
+
+        if !name.is_empty() {
+            match existing {
+                Some(i) => ports[i].name = Some(name),
+                None => {
+                    port.name = Some(name);
+                    ports.push(port);
+                }
+            }
+            save(ports)?;
+            named += 1;
+        }
+
+        if !prompt.confirm("Name another port?", true)? {
+            return Ok(named);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{udev::UdevEvent, usb::UsbDevice};
+
+    fn event(kind: UsbEvent, sysname: &str) -> UdevEvent {
+        let mut port = UsbPort::default();
+        port.set_property("sysname", sysname);
+        port.set_property("devpath", format!("/devices/pci0000:00/usb2/{sysname}"));
+        UdevEvent {
+            event_kind: kind,
+            device: UsbDevice::default(),
+            port,
+            nodes: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn label_ports_replay() {
+        let mut source = EventSource::replay([
+            event(UsbEvent::Add, "2-1"),
+            event(UsbEvent::Remove, "2-1"),
+            event(UsbEvent::Add, "2-2"),
+            event(UsbEvent::Add, "2-3"),
+            event(UsbEvent::Add, "2-1"),
+        ]);
+        // 2-1 is Left; 2-2 tries the taken name first; 2-3 is skipped; 2-1 is
+        // renamed
+        let answers = "Left\n\nLeft\nRight\ny\n\ny\nFront Left\nn\n";
+        let mut out = Vec::new();
+        let mut prompt = Prompt::new(answers.as_bytes(), &mut out);
+        let mut ports = Vec::new();
+        let mut saves = 0;
+
+        let named = label_ports(&mut source, &mut prompt, &mut ports, |_| {
+            saves += 1;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!((named, saves), (3, 3));
+        let names: Vec<_> = ports.iter().map(|p| p.name.as_deref().unwrap()).collect();
+        assert_eq!(names, ["Front Left", "Right"]);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("'Left' is already the name of another port"));
+        assert!(out.contains("Port 2-1 is already named 'Left'"));
+    }
+}
@@ -1,20 +1,24 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use anyhow::bail;
 use clap::Args;
 use tokio::{
+    fs::{self, File, OpenOptions},
     io::AsyncWriteExt,
     signal::unix::{signal, SignalKind},
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
 };
 
 use crate::{
-    cli::{Cmd, ForObject},
+    cli::{Cmd, FilterArgs, ForObject},
     ctx::Ctx,
-    listener::UdevListener,
-    printer::OutFormat,
+    listener::{UdevFilter, UdevListener},
+    printer::{print_doc, Entry, OutFormat, Table},
+    rule::Match,
     shutdown::Shutdown,
+    template::Template,
     udev::UdevEvent,
-    usb::UsbEvent,
+    usb::{self, UsbDevice, UsbEvent, UsbInventory, UsbPort},
 };
 
 /// Listen for events and display them to stdout
@@ -28,15 +32,92 @@ pub struct UsbWatchListen {
     #[arg(long, short, value_enum, value_name = "KIND", default_value = "all")]
     pub event: UsbEvent,
 
-    /// Save the even information to a file at the following path
+    /// Save the event information to a file at the following path
+    ///
+    /// Each event is written to the file as soon as it's received, as its own
+    /// document (YAML), or line (JSON), so the file is always a valid
+    /// inventory even if interrupted. Ports and devices already in the file
+    /// are not written again.
     #[arg(long, short = 'O', value_name = "PATH")]
     pub output: Option<PathBuf>,
 
+    /// Add to the end of the --output file instead of replacing it
+    ///
+    /// Events are written in the format already used in the file (YAML or
+    /// NDJSON) whatever --format is, so it can still be read back.
+    #[arg(long, short, requires = "output")]
+    pub append: bool,
+
+    /// Display each event as a single line built from TEMPLATE
+    ///
+    /// Fields of the event are referenced with `{{...}}` using the same names
+    /// as the YAML or JSON output, i.e. '{{event}} {{device.ID_VENDOR_ID}}
+    /// {{port.sysname}}'
+    #[arg(long, short, value_name = "TEMPLATE", conflicts_with = "output")]
+    pub template: Option<Template>,
+
+    /// Only display events for devices defined in PATH (may be repeated)
+    #[arg(long, value_name = "PATH")]
+    pub match_devices: Vec<PathBuf>,
+
+    /// Only display events for ports defined in PATH (may be repeated)
+    #[arg(long, value_name = "PATH")]
+    pub match_ports: Vec<PathBuf>,
+
+    /// Only display events where the device or port property KEY is VALUE
+    ///
+    /// May be repeated, in which case all must match, i.e. `--where
+    /// ID_VENDOR_ID=0781 --where sysname=2-1`
+    #[arg(long = "where", short, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub properties: Vec<(String, String)>,
+
+    #[command(flatten)]
+    pub udev: FilterArgs,
+
     /// Only listen for N events and exit (0 is infinite)
     #[arg(long, short, value_name = "N", default_value = "0")]
     pub num_events: usize,
 }
 
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.into(), v.into())),
+        _ => Err(format!("expected KEY=VALUE but found '{s}'")),
+    }
+}
+
+impl UsbWatchListen {
+    /// Which events should be displayed, reusing the same matching rules use
+    fn filter(&self) -> anyhow::Result<Match> {
+        let mut devices = Vec::new();
+        let mut ports = Vec::new();
+        for path in &self.match_devices {
+            devices.append(&mut UsbInventory::from_path(path)?.devices());
+        }
+        for path in &self.match_ports {
+            ports.append(&mut UsbInventory::from_path(path)?.ports());
+        }
+
+        let mut device = UsbDevice::default();
+        let mut port = UsbPort::default();
+        for (key, value) in &self.properties {
+            if !device.set_property(key, value) && !port.try_set_property(key, value)? {
+                bail!("'{key}' is not a known device or port property");
+            }
+        }
+        if !device.is_empty() {
+            devices.push(device);
+        }
+        if !port.is_empty() {
+            ports.push(port);
+        }
+
+        Ok(Match::new(self.event)
+            .with_devices(devices)
+            .with_ports(ports))
+    }
+}
+
 impl Cmd for UsbWatchListen {
     fn update_ctx(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
         if self.num_events == 0 {
@@ -49,6 +130,11 @@ impl Cmd for UsbWatchListen {
     }
 
     fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        if ctx.format == OutFormat::Tree {
+            bail!("'--format tree' is only supported by scan; use table to list events");
+        }
+        let filter = self.filter()?;
+
         if self.output.is_some() {
             cli_println!("Listening for udev events...");
         }
@@ -62,6 +148,18 @@ impl Cmd for UsbWatchListen {
                 let mut sighup = signal(SignalKind::hangup()).unwrap();
                 let mut end = false;
 
+                let mut capture = match &self.output {
+                    Some(path) => {
+                        match Capture::open(path, self.append, self.only, ctx.format).await {
+                            Ok(c) => Some(c),
+                            Err(err) => {
+                                cli_bail!("failed to open {}; {}", path.display(), err);
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
                 loop {
                     let (udev_event_tx, udev_event_rx) = broadcast::channel(32); // 32 picked by fair diceroll
                     let (notify_shutdown, _) = broadcast::channel(1);
@@ -71,6 +169,12 @@ impl Cmd for UsbWatchListen {
                         shutdown: Shutdown::new(notify_shutdown.subscribe()),
                         shutdown_complete_tx: shutdown_complete_tx.clone(),
                         udev_event_tx: udev_event_tx.clone(),
+                        filter: watch::channel(self.udev.apply(UdevFilter {
+                            events: vec![self.event],
+                            ..Default::default()
+                        }))
+                        .1,
+                        events: None,
                     };
 
                     let mut handler = Handler {
@@ -86,7 +190,7 @@ impl Cmd for UsbWatchListen {
                                 cli_error!("listener failed; {}", err);
                             }
                         }
-                        res = handler.run(self, ctx) => {
+                        res = handler.run(self, ctx, &filter, capture.as_mut()) => {
                             if let Err(err) = res {
                                 cli_error!("handler failed; {}", err);
                             } else {
@@ -147,13 +251,17 @@ impl Handler {
         &mut self,
         args: &UsbWatchListen,
         ctx: &Ctx,
+        filter: &Match,
+        mut capture: Option<&mut Capture>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
         tokio::pin!(shutdown);
 
         let mut count = 0;
-        let mut ports = vec![];
-        let mut devices = vec![];
+
+        if args.output.is_none() && args.template.is_none() && ctx.format == OutFormat::Table {
+            Table::new(args.only, true).print_header();
+        }
 
         while count < ctx.num_events && !shutdown.is_shutdown() {
             let event = tokio::select! {
@@ -164,13 +272,16 @@ impl Handler {
             };
             cli_debugln!("Received udev event");
 
-            cli_debug!("Checking if event type qualifies for printing...");
-            if args.event == event.event_kind || args.event == UsbEvent::All {
+            cli_debug!("Checking if event qualifies for printing...");
+            // The listener only passes on the kinds of events asked for
+            if filter.matches_selected(&event) && filter.matches_nodes(&event.nodes) {
                 cli_debugln!("Yes");
-                if args.output.is_some() {
-                    cli_println!("Recvied 1 event");
-                    ports.push(event.port);
-                    devices.push(event.device);
+                if let Some(capture) = capture.as_deref_mut() {
+                    if capture.record(event).await? {
+                        cli_println!("Recorded 1 event");
+                    } else {
+                        cli_println!("Skipped 1 event; already recorded");
+                    }
                 } else {
                     print_event(event, args, ctx).await;
                 }
@@ -180,83 +291,160 @@ impl Handler {
             }
         }
 
-        if let Some(path) = &args.output {
-            cli_println!("Writing output to {}...", path.display());
+        Ok(())
+    }
+}
 
-            let mut f = String::new();
+/// An inventory file that events are appended to as they arrive
+struct Capture {
+    file: File,
+    only: ForObject,
+    format: OutFormat,
+    ports: Vec<UsbPort>,
+    devices: Vec<UsbDevice>,
+}
 
-            f.push_str("---\n");
-            if args.only == ForObject::Ports || args.only == ForObject::All {
-                f.push_str("ports:\n");
-                for port in ports {
-                    f.push_str("  - ");
-                    let yaml = serde_yaml::to_string(&port).unwrap();
-                    for (i, line) in yaml.lines().skip(1).enumerate() {
-                        if i != 0 {
-                            f.push_str("    ");
-                        }
-                        f.push_str(line);
-                        f.push('\n');
-                    }
-                }
-            }
-            if args.only == ForObject::Devices || args.only == ForObject::All {
-                f.push_str("devices:\n");
-                for device in devices {
-                    f.push_str("  - ");
-                    let yaml = serde_yaml::to_string(&device).unwrap();
-                    for (i, line) in yaml.lines().skip(1).enumerate() {
-                        if i != 0 {
-                            f.push_str("    ");
-                        }
-                        f.push_str(line);
-                        f.push('\n');
-                    }
-                }
+impl Capture {
+    async fn open(
+        path: &Path,
+        append: bool,
+        only: ForObject,
+        format: OutFormat,
+    ) -> anyhow::Result<Self> {
+        let mut format = format.for_file();
+        let mut ports = Vec::new();
+        let mut devices = Vec::new();
+        let mut needs_newline = false;
+        if append && path.exists() {
+            let buf = fs::read_to_string(path).await?;
+            let existing = UsbInventory::from_docs(&buf)?;
+            ports = existing.ports.unwrap_or_default();
+            devices = existing.devices.unwrap_or_default();
+            needs_newline = !buf.is_empty() && !buf.ends_with('\n');
+            // A file mixing the two can't be read back
+            if !buf.trim().is_empty() {
+                format = if usb::is_ndjson(&buf) {
+                    OutFormat::Ndjson
+                } else {
+                    OutFormat::Yaml
+                };
             }
-            f.push('\n');
+        }
 
-            let mut file = tokio::fs::File::create(path).await?;
-            file.write_all(f.as_bytes()).await?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .await?;
+        if needs_newline {
+            file.write_all(b"\n").await?;
         }
 
-        Ok(())
+        Ok(Self {
+            file,
+            only,
+            format,
+            ports,
+            devices,
+        })
+    }
+
+    /// Writes any new port or device from `event`, returning `false` if there
+    /// was nothing new
+    async fn record(&mut self, mut event: UdevEvent) -> anyhow::Result<bool> {
+        // The file is meant to be matched against on a later plug in
+        event.port.strip_volatile();
+        event.device.strip_volatile();
+
+        let port =
+            (self.only.ports() && !event.port.is_empty() && !self.ports.contains(&event.port))
+                .then_some(event.port);
+        let device = (self.only.devices()
+            && !event.device.is_empty()
+            && !self.devices.contains(&event.device))
+        .then_some(event.device);
+        if port.is_none() && device.is_none() {
+            return Ok(false);
+        }
+
+        let doc = self.format.render(&UsbInventory {
+            ports: port.clone().map(|p| vec![p]),
+            devices: device.clone().map(|d| vec![d]),
+            ..Default::default()
+        })?;
+        self.file.write_all(doc.as_bytes()).await?;
+        self.file.flush().await?;
+
+        self.ports.extend(port);
+        self.devices.extend(device);
+        Ok(true)
     }
 }
 
 async fn print_event(udev_dev: UdevEvent, args: &UsbWatchListen, ctx: &Ctx) {
-    match ctx.format {
-        OutFormat::Raw => {
-            if args.only == ForObject::Ports || args.only == ForObject::All {
-                cli_println!("{:#?}", udev_dev.port);
-            }
-            if args.only == ForObject::Devices || args.only == ForObject::All {
-                cli_println!("{:#?}", udev_dev.device);
-            }
+    if let Some(template) = &args.template {
+        match template.render(&udev_dev) {
+            Ok(line) => cli_println!("{line}"),
+            Err(err) => cli_error!("failed to render template; {}", err),
         }
-        OutFormat::Yaml => {
-            if args.only == ForObject::Ports || args.only == ForObject::All {
-                cli_print!("---\nports:\n  - ");
-                let yaml = serde_yaml::to_string(&udev_dev.port).unwrap();
-                for (i, line) in yaml.lines().skip(1).enumerate() {
-                    if i == 0 {
-                        cli_println!("{}", line);
-                    } else {
-                        cli_println!("    {}", line);
-                    }
-                }
-            }
-            if args.only == ForObject::Devices || args.only == ForObject::All {
-                cli_print!("---\ndevices:\n  - ");
-                let yaml = serde_yaml::to_string(&udev_dev.device).unwrap();
-                for (i, line) in yaml.lines().skip(1).enumerate() {
-                    if i == 0 {
-                        cli_println!("{}", line);
-                    } else {
-                        cli_println!("    {}", line);
-                    }
-                }
-            }
+        return;
+    }
+
+    if ctx.format == OutFormat::Table {
+        Table::new(args.only, true).print_row(&Entry {
+            port: &udev_dev.port,
+            device: &udev_dev.device,
+            event: Some(udev_dev.event_kind),
+        });
+        return;
+    }
+
+    let mut inventory = args
+        .only
+        .inventory(vec![udev_dev.port], vec![udev_dev.device]);
+    inventory.event = Some(udev_dev.event_kind);
+    if let Err(err) = print_doc(&inventory, ctx.format) {
+        cli_error!("failed to print event; {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn added(vendor: &str) -> UdevEvent {
+        let mut device = UsbDevice::new("stick");
+        device.set_property("ID_VENDOR_ID", vendor);
+        UdevEvent {
+            event_kind: UsbEvent::Add,
+            device,
+            port: UsbPort::default(),
+            nodes: Default::default(),
         }
     }
+
+    #[tokio::test]
+    async fn append_keeps_the_file_format() {
+        let dir = TempDir::new("listen");
+        let path = dir.join("capture.yml");
+
+        let mut capture = Capture::open(&path, false, ForObject::Devices, OutFormat::Yaml)
+            .await
+            .unwrap();
+        assert!(capture.record(added("0781")).await.unwrap());
+        drop(capture);
+
+        let mut capture = Capture::open(&path, true, ForObject::Devices, OutFormat::Json)
+            .await
+            .unwrap();
+        assert!(!capture.record(added("0781")).await.unwrap());
+        assert!(capture.record(added("1050")).await.unwrap());
+
+        let buf = std::fs::read_to_string(&path).unwrap();
+        assert!(!usb::is_ndjson(&buf));
+        assert_eq!(UsbInventory::from_docs(&buf).unwrap().devices().len(), 2);
+    }
 }
@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use clap::Args;
 use serde::Serialize;
 
-use crate::{cli::Cmd, ctx::Ctx, usb::UsbEvent};
+use crate::{cli::Cmd, ctx::Ctx, printer::print_doc, usb::UsbEvent};
 
 fn default_shell(p: &PathBuf) -> bool { p == &PathBuf::from("/bin/sh") }
 
@@ -41,7 +41,11 @@ pub struct UsbWatchCreateRule {
 }
 
 impl Cmd for UsbWatchCreateRule {
-    fn run(&self, _ctx: &mut Ctx) -> anyhow::Result<()> {
+    fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        #[derive(Serialize, PartialEq, Debug)]
+        struct CliRules {
+            rules: Vec<CliRule>,
+        }
         #[derive(Serialize, PartialEq, Debug)]
         struct CliRule {
             pub name: String,
@@ -92,15 +96,7 @@ impl Cmd for UsbWatchCreateRule {
             command_shell: self.shell.clone(),
             command: self.execute.clone(),
         };
-        let yaml = serde_yaml::to_string(&r).unwrap();
-
-        cli_print!("---\nrules:\n  - ");
-        for (i, line) in yaml.lines().enumerate() {
-            if i != 0 {
-                cli_print!("    ");
-            }
-            cli_println!("{line}");
-        }
+        print_doc(&CliRules { rules: vec![r] }, ctx.format)?;
 
         Ok(())
     }
@@ -1,142 +1,506 @@
-use std::{env, path::PathBuf, process::Stdio, sync::Arc};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
 use clap::Args;
 use parking_lot::Mutex;
 use tokio::{
-    process::Command,
     signal::unix::{signal, SignalKind},
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch, Notify},
+    time::Interval,
 };
-use tracing::{debug, error, info, span, Level};
+use tracing::{debug, error, info, span, warn, Level};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    cli::Cmd, ctx::Ctx, listener::UdevListener, shutdown::Shutdown, state::State, udev::UdevEvent,
-    usb::UsbEvent,
+    audit::{Audit, Entry},
+    authorize::{Authorize, Policy, Sysfs},
+    cli::{Cmd, FilterArgs},
+    config::{Config, LogFormat, DEFAULT_CONFIG, DEFAULT_SHUTDOWN_GRACE},
+    control::{ControlListener, RuleFired, Trigger, DEFAULT_SOCKET},
+    ctx::Ctx,
+    exec::Executor,
+    listener::{UdevFilter, UdevListener},
+    rule::Rule,
+    secure,
+    shutdown::Shutdown,
+    state::State,
+    systemd::Notifier,
+    udev::UdevEvent,
+    usb::{DeviceNode, UsbEvent},
+    watch::FileWatcher,
 };
 
+/// How often to look for the child nodes a rule is waiting on
+const NODE_POLL: Duration = Duration::from_millis(250);
+
 /// Begin matching against rules and running actions
-#[derive(Args, Debug)]
+///
+/// Anything not given here or in the environment is taken from the config
+/// file, when there is one.
+#[derive(Args, Clone, Debug)]
 pub struct UsbWatchRun {
-    /// Rules file to use
-    #[arg(long, short)]
-    pub rules: PathBuf,
+    /// Rules file to use, or a directory of `*.yml` rules files
+    ///
+    /// May be given more than once. Files in a directory are loaded in lexical
+    /// order, and rule names must be unique across all of the files.
+    #[arg(long, short, value_name = "PATH", env = "USBWATCH_RULES")]
+    pub rules: Vec<PathBuf>,
     /// Devices to match against
-    #[arg(long, short)]
+    #[arg(long, short, env = "USBWATCH_DEVICES")]
     pub devices: Option<PathBuf>,
     /// Ports to match against
-    #[arg(long, short)]
+    #[arg(long, short, env = "USBWATCH_PORTS")]
     pub ports: Option<PathBuf>,
+    #[command(flatten)]
+    pub udev: FilterArgs,
+    /// Control socket that `usbwatch ctl` connects to [default:
+    /// /run/usbwatch.sock]
+    #[arg(long, value_name = "PATH", env = "USBWATCH_SOCKET")]
+    pub socket: Option<PathBuf>,
+    /// Reload whenever the rules, devices or ports files change
+    ///
+    /// Files included by the rules (i.e. `include_devices`) are watched too.
+    /// Reloading works just like on SIGHUP. `--watch=false` turns it off
+    /// when the config file turns it on.
+    #[arg(
+        long,
+        env = "USBWATCH_WATCH",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub watch: Option<bool>,
+    /// Seconds running commands get to finish when shutting down [default: 10]
+    ///
+    /// Commands still running after that are sent SIGTERM, and then killed.
+    #[arg(long, value_name = "SECS", env = "USBWATCH_SHUTDOWN_GRACE")]
+    pub shutdown_grace: Option<u64>,
+    /// What happens to devices no rule authorizes or deauthorizes [default:
+    /// allow-all]
+    ///
+    /// With `default-deny`, devices start out deauthorized when plugged in,
+    /// and only hubs and the devices in the --allow files are authorized.
+    #[arg(long, value_enum, value_name = "POLICY", env = "USBWATCH_POLICY")]
+    pub policy: Option<Policy>,
+    /// Devices file of the devices `default-deny` allows (may be repeated)
+    #[arg(long, value_name = "PATH", env = "USBWATCH_ALLOW")]
+    pub allow: Vec<PathBuf>,
+    /// Append a JSON lines record of every event, decision and action to
+    /// PATH
+    ///
+    /// Rotation and hash chaining are set up in the config file.
+    #[arg(long, value_name = "PATH", env = "USBWATCH_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+    /// Config file with defaults for `run` [default: /etc/usbwatch/config.yml]
+    ///
+    /// Unlike the default, a config file given here has to exist.
+    #[arg(long, value_name = "PATH", env = "USBWATCH_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Load files that users other than root can change, even when running
+    /// as root
+    ///
+    /// As root, the config, rules, devices, ports and allow files, every file
+    /// the rules include, and the shells commands run with have to be owned
+    /// by root and not be writable by anyone else, along with the
+    /// directories they're in. Otherwise anyone able to change them could
+    /// run commands as root.
+    #[arg(long, env = "USBWATCH_INSECURE_ALLOW")]
+    pub insecure_allow: bool,
 }
 
 impl Cmd for UsbWatchRun {
-    fn update_ctx(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
-        ctx.tracing = true;
-        Ok(())
-    }
-
     fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
+        let config = Config::load(self.config.as_deref())?;
+        let this = self.with_config(&config)?;
+
         // SAFETY: the program is single threaded at this point so no other threads are
         // currently reading or writing to the environment.
         match ctx.verbose {
-            0 => (),
+            0 => {
+                if let (None, Some(level)) = (env::var_os("RUST_LOG"), config.run.log_level) {
+                    env::set_var("RUST_LOG", format!("usbwatch={level}"));
+                }
+            }
             1 => env::set_var("RUST_LOG", "usbwatch=info"),
             2 => env::set_var("RUST_LOG", "usbwatch=debug"),
             _ => env::set_var("RUST_LOG", "usbwatch=trace"),
         }
 
-        tracing_subscriber::fmt::init();
+        init_tracing(config.run.log_format);
+        // Errors before this point have nowhere to be logged yet
+        ctx.tracing = true;
+        let notifier = Notifier::from_env().map(Arc::new);
 
         tokio::runtime::Builder::new_current_thread()
             .enable_io()
+            .enable_time()
             .build()
             .unwrap()
             .block_on(async {
                 debug!("Creating signal listeners");
-                // let (event_tx, event_rx) = broadcast::channel(32);
                 let mut sigint = signal(SignalKind::interrupt()).unwrap();
                 let mut sighup = signal(SignalKind::hangup()).unwrap();
+                let mut sigterm = signal(SignalKind::terminate()).unwrap();
+                let mut sigquit = signal(SignalKind::quit()).unwrap();
+
+                let config_file = self.config.as_deref().unwrap_or(Path::new(DEFAULT_CONFIG));
+                let mut startup = vec![config.exec.shell()];
+                startup.extend(config_file.exists().then(|| config_file.to_path_buf()));
+                this.refuse_insecure(startup)?;
+                let state = Arc::new(Mutex::new(this.load()?));
+                let audit = match &this.audit_log {
+                    Some(path) => Audit::open(path, &config.audit)?,
+                    None => Audit::default(),
+                };
+                let sysfs = Sysfs::new(config.authorize.sysfs());
+                if this.policy() == Policy::DefaultDeny {
+                    // Before scanning, so anything plugged in from here on
+                    // waits, deauthorized, for its add event
+                    sysfs
+                        .set_default(Authorize::Deny)
+                        .context("failed to make devices default to deauthorized")?;
+                }
 
-                debug!("Creating blank State");
-                let state = Arc::new(Mutex::new(State::new()));
+                let (filter_tx, filter_rx) = watch::channel(this.filter(&state.lock()));
+                let capacity = config.run.channel_capacity();
+                let (udev_event_tx, udev_event_rx) = broadcast::channel(capacity);
+                let (rule_fired_tx, _) = broadcast::channel(capacity);
+                let (notify_shutdown, _) = broadcast::channel(1);
+                let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+                let reloaded = Arc::new(Notify::new());
+                let executor = Executor::new(config.exec, sysfs, audit);
+                let mut watchdog = notifier
+                    .as_ref()
+                    .and_then(|n| n.watchdog_interval())
+                    .map(tokio::time::interval);
 
-                loop {
-                    let (udev_event_tx, udev_event_rx) = broadcast::channel(32); // 32 picked by fair diceroll
-                    let state = state.clone();
-                    {
+                let mut listener = UdevListener {
+                    shutdown: Shutdown::new(notify_shutdown.subscribe()),
+                    shutdown_complete_tx: shutdown_complete_tx.clone(),
+                    udev_event_tx: udev_event_tx.clone(),
+                    filter: filter_rx,
+                    events: None,
+                };
+                // Also before scanning, so nothing plugged in or removed
+                // from here on is missed; its event waits in the socket
+                listener
+                    .listen()
+                    .context("failed to listen for udev events")?;
+                info!("Listening for udev events");
+
+                match super::scan::attached() {
+                    Ok(attached) => {
                         let mut s = state.lock();
-                        if let Some(ref p) = self.devices {
-                            info!("Loading devices from {:?}", p);
-                            s.devices_from_file(p);
-                        }
-                        if let Some(ref p) = self.ports {
-                            info!("Loading ports from {:?}", p);
-                            s.ports_from_file(p);
+                        for (port, device) in attached {
+                            s.attach(port, device);
                         }
-                        info!("Loading rules from {:?}", self.rules);
-                        s.rules_from_file(self.rules.clone());
                     }
+                    Err(err) => error!(cause = %err, "failed to scan attached devices"),
+                }
+
+                let mut watcher = if this.watch() {
+                    let mut watcher = FileWatcher::new()?;
+                    watcher.watch(this.watched(&state.lock()))?;
+                    Some(watcher)
+                } else {
+                    None
+                };
+
+                let mut control = ControlListener {
+                    socket: this.socket(),
+                    shutdown: Shutdown::new(notify_shutdown.subscribe()),
+                    shutdown_complete_tx: shutdown_complete_tx.clone(),
+                    udev_event_tx,
+                    rule_fired_tx: rule_fired_tx.clone(),
+                };
+
+                let mut handler = Handler {
+                    notify_shutdown,
+                    shutdown_complete_tx,
+                    shutdown_complete_rx,
+                    udev_event_rx,
+                    rule_fired_tx,
+                    state: state.clone(),
+                    reloaded: reloaded.clone(),
+                    executor: executor.clone(),
+                    notifier: notifier.clone(),
+                };
 
-                    let (notify_shutdown, _) = broadcast::channel(1);
-                    let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
-
-                    let mut listener = UdevListener {
-                        shutdown: Shutdown::new(notify_shutdown.subscribe()),
-                        shutdown_complete_tx: shutdown_complete_tx.clone(),
-                        udev_event_tx: udev_event_tx.clone(),
-                    };
-
-                    let mut handler = Handler {
-                        notify_shutdown,
-                        shutdown_complete_tx,
-                        shutdown_complete_rx,
-                        udev_event_rx,
-                        state: state.clone(),
-                    };
-
-                    tokio::select! {
-                        res = listener.run() => {
-                            if let Err(err) = res {
-                                error!(cause = %err, "listener failed");
+                {
+                    // Reloading swaps the rules in place, so the tasks (and
+                    // the udev socket) keep running and no events are missed
+                    let listener_run = listener.run();
+                    let control_run = control.run();
+                    let handler_run = handler.run();
+                    tokio::pin!(listener_run, control_run, handler_run);
+
+                    // What's attached is known, and nothing since has been
+                    // missed
+                    if let Some(notifier) = &notifier {
+                        notifier.status(&state.lock().status());
+                        notifier.ready();
+                    }
+
+                    loop {
+                        tokio::select! {
+                            res = &mut listener_run => {
+                                if let Err(err) = res {
+                                    error!(cause = %err, "listener failed");
+                                }
+                                break;
                             }
-                        }
-                        res = handler.run() => {
-                            if let Err(err) = res {
-                                error!(cause = %err, "handler failed");
+                            res = &mut control_run => {
+                                if let Err(err) = res {
+                                    error!(cause = %err, "control socket failed");
+                                }
+                                break;
+                            }
+                            res = &mut handler_run => {
+                                if let Err(err) = res {
+                                    error!(cause = %err, "handler failed");
+                                }
+                                break;
+                            }
+                            _ = tick(watchdog.as_mut()) => {
+                                if let Some(notifier) = &notifier {
+                                    notifier.watchdog();
+                                }
+                            }
+                            _ = sighup.recv() => {
+                                info!("SIGHUP received; reloading");
+                                this.reload(&state, &filter_tx, &reloaded, notifier.as_deref());
+                                this.rewatch(&mut watcher, &state);
+                            }
+                            res = changed(watcher.as_mut()) => {
+                                if let Err(err) = res {
+                                    error!(cause = %err, "watching files failed; no longer watching");
+                                    watcher = None;
+                                    continue;
+                                }
+                                info!("Watched files changed; reloading");
+                                this.reload(&state, &filter_tx, &reloaded, notifier.as_deref());
+                                this.rewatch(&mut watcher, &state);
+                            }
+                            _ = sigint.recv() => {
+                                // SIGINT has been received.
+                                info!("SIGINT received; shutting down");
+                                break;
+                            }
+                            _ = sigterm.recv() => {
+                                info!("SIGTERM received; shutting down");
+                                break;
+                            }
+                            _ = sigquit.recv() => {
+                                info!("SIGQUIT received; shutting down");
+                                break;
                             }
                         }
-                        _ = sighup.recv() => {
-                            info!("SIGHUP received; reloading");
-                        }
-                        _ = sigint.recv() => {
-                            // SIGINT has been received.
-                            info!("SIGINT received; shutting down");
-                            break;
-                        }
-                    };
+                    }
+                }
+                if let Some(notifier) = &notifier {
+                    notifier.stopping();
+                }
 
-                    let Handler {
-                        mut shutdown_complete_rx,
-                        shutdown_complete_tx,
-                        notify_shutdown,
-                        ..
-                    } = handler;
+                let Handler {
+                    mut shutdown_complete_rx,
+                    shutdown_complete_tx,
+                    notify_shutdown,
+                    rule_fired_tx,
+                    ..
+                } = handler;
 
-                    drop(notify_shutdown);
-                    drop(shutdown_complete_tx);
+                drop(notify_shutdown);
+                drop(shutdown_complete_tx);
 
-                    let UdevListener {
-                        shutdown_complete_tx,
-                        shutdown,
-                        ..
-                    } = listener;
+                let UdevListener {
+                    shutdown_complete_tx,
+                    shutdown,
+                    ..
+                } = listener;
 
-                    drop(shutdown);
-                    drop(shutdown_complete_tx);
+                drop(shutdown);
+                drop(shutdown_complete_tx);
 
-                    let _ = shutdown_complete_rx.recv().await;
-                }
-            });
-        Ok(())
+                let ControlListener {
+                    shutdown_complete_tx,
+                    shutdown,
+                    ..
+                } = control;
+
+                drop(shutdown);
+                drop(shutdown_complete_tx);
+
+                let _ = shutdown_complete_rx.recv().await;
+
+                // No more events are handled, but commands already started
+                // (and those of shutdown rules) get a chance to finish
+                fire_lifecycle(&state.lock(), UsbEvent::Shutdown, &executor, &rule_fired_tx);
+                executor.shutdown(this.shutdown_grace()).await;
+                Ok(())
+            })
+    }
+}
+
+impl UsbWatchRun {
+    /// Fills in whatever wasn't given on the command line or in the
+    /// environment from the config file
+    fn with_config(&self, config: &Config) -> anyhow::Result<Self> {
+        let (config, authorize, audit) = (&config.run, &config.authorize, &config.audit);
+        let mut this = self.clone();
+        if this.rules.is_empty() {
+            this.rules.clone_from(&config.rules);
+        }
+        if this.rules.is_empty() {
+            bail!("no rules to run; use --rules or set 'rules' under 'run' in the config file");
+        }
+        this.devices = this.devices.or_else(|| config.devices.clone());
+        this.ports = this.ports.or_else(|| config.ports.clone());
+        this.socket = this.socket.or_else(|| config.socket.clone());
+        this.watch = this.watch.or(config.watch);
+        this.shutdown_grace = this.shutdown_grace.or(config.shutdown_grace);
+        this.policy = this.policy.or(authorize.policy);
+        if this.allow.is_empty() {
+            this.allow.clone_from(&authorize.allow);
+        }
+        this.audit_log = this.audit_log.or_else(|| audit.path.clone());
+        Ok(this)
+    }
+
+    fn policy(&self) -> Policy { self.policy.unwrap_or_default() }
+
+    fn watch(&self) -> bool { self.watch.unwrap_or(false) }
+
+    fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE))
+    }
+
+    fn socket(&self) -> PathBuf { self.socket.clone().unwrap_or_else(|| DEFAULT_SOCKET.into()) }
+
+    /// Loads the devices, ports and rules files into a fresh [`State`]
+    fn load(&self) -> anyhow::Result<State> {
+        let mut s = State::new();
+        if let Some(ref p) = self.devices {
+            info!("Loading devices from {:?}", p);
+            s.devices_from_file(p)?;
+        }
+        if let Some(ref p) = self.ports {
+            info!("Loading ports from {:?}", p);
+            s.ports_from_file(p)?;
+        }
+        info!("Loading rules from {:?}", self.rules);
+        s.rules_from_paths(&self.rules)?;
+        if self.policy() == Policy::DefaultDeny {
+            info!("Loading allowed devices from {:?}", self.allow);
+            s.allow_from_paths(&self.allow)?;
+        }
+        self.refuse_insecure(self.loaded(&s))?;
+        Ok(s)
+    }
+
+    /// Every file loading `state` read, or that its commands run with
+    fn loaded(&self, state: &State) -> Vec<PathBuf> {
+        let mut files = secure::rules_paths(&self.rules, &state.rules);
+        files.extend(self.devices.iter().chain(&self.ports).cloned());
+        if self.policy() == Policy::DefaultDeny {
+            files.extend(self.allow.iter().cloned());
+        }
+        files
+    }
+
+    /// Fails when running as root and users other than root could change any
+    /// of `files`, unless --insecure-allow was given
+    fn refuse_insecure(&self, files: Vec<PathBuf>) -> anyhow::Result<()> {
+        if !secure::is_root() {
+            return Ok(());
+        }
+        let problems = secure::check_all(&files, 0);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if self.insecure_allow {
+            for problem in &problems {
+                warn!(%problem, "Loading insecure file anyway because of --insecure-allow");
+            }
+            return Ok(());
+        }
+        let list: Vec<_> = problems.iter().map(|p| format!("\n  {p}")).collect();
+        bail!(
+            "refusing to run as root with files users other than root can change:{}\n\
+             make them owned by root and writable only by root, or use --insecure-allow",
+            list.concat()
+        )
+    }
+
+    /// The udev events the rules in `state` need
+    fn filter(&self, state: &State) -> UdevFilter {
+        // Adds and removes are always needed to keep track of which devices
+        // are plugged in where
+        self.udev.apply(
+            UdevFilter::for_rules(&state.rules).with_events([UsbEvent::Add, UsbEvent::Remove]),
+        )
+    }
+
+    /// The files that are reloaded when changed with --watch
+    fn watched(&self, state: &State) -> Vec<PathBuf> {
+        let mut files = self.rules.clone();
+        files.extend(self.devices.iter().chain(&self.ports).cloned());
+        files.extend(self.allow.iter().cloned());
+        for rule in &state.rules {
+            files.extend(rule.includes().into_iter().map(PathBuf::from));
+        }
+        files
+    }
+
+    /// Watches whatever the rules now include
+    fn rewatch(&self, watcher: &mut Option<FileWatcher>, state: &Mutex<State>) {
+        let Some(w) = watcher else {
+            return;
+        };
+        if let Err(err) = w.watch(self.watched(&state.lock())) {
+            error!(cause = %err, "watching files failed; no longer watching");
+            *watcher = None;
+        }
+    }
+
+    /// Loads the files again and swaps them in, or keeps using what's already
+    /// loaded if any of them are invalid
+    fn reload(
+        &self,
+        state: &Mutex<State>,
+        filter_tx: &watch::Sender<UdevFilter>,
+        reloaded: &Notify,
+        notifier: Option<&Notifier>,
+    ) {
+        if let Some(notifier) = notifier {
+            notifier.reloading();
+        }
+
+        match self.load() {
+            Ok(fresh) => {
+                let filter = self.filter(&fresh);
+                info!(num_rules = %fresh.rules.len(), "Reloaded");
+                state.lock().replace_config(fresh);
+                // Only fails once the listener is gone, when we're shutting
+                // down anyway
+                let _ = filter_tx.send(filter);
+                reloaded.notify_one();
+            }
+            Err(err) => {
+                error!(cause = %format!("{err:#}"), "Reload failed; keeping the current rules");
+            }
+        }
+
+        // Either way the daemon is back to handling events
+        if let Some(notifier) = notifier {
+            notifier.status(&state.lock().status());
+            notifier.ready();
+        }
     }
 }
 
@@ -145,34 +509,158 @@ struct Handler {
     shutdown_complete_tx: mpsc::Sender<()>,
     shutdown_complete_rx: mpsc::Receiver<()>,
     udev_event_rx: broadcast::Receiver<UdevEvent>,
+    /// Tells control socket subscribers which rules fired
+    rule_fired_tx: broadcast::Sender<RuleFired>,
     state: Arc<Mutex<State>>,
+    /// Told when the rules have been reloaded, so state rules are checked
+    /// again
+    reloaded: Arc<Notify>,
+    executor: Executor,
+    /// Kept up to date with how many devices are attached
+    notifier: Option<Arc<Notifier>>,
 }
 
-async fn exec(cmd: String, shell: PathBuf) -> Result<(), ()> {
-    let span = span!(Level::TRACE, "fn exec");
-    let _enter = span.enter();
-
-    debug!("Executing command");
-    let mut child = Command::new(&shell)
-        .arg("-c")
-        .arg(cmd)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn child process");
-
-    info!("Executing command");
-    debug!("Waiting for child to exit");
-    let status = child.wait().await.unwrap();
-    if status.success() {
-        info!("Command completed successfully");
-    } else {
-        info!(
-            "Command completed with error code {code:?}",
-            code = status.code()
-        );
+/// Logs to stderr in `format`, at the levels given by `RUST_LOG`
+fn init_tracing(format: LogFormat) {
+    let targets = env::var("RUST_LOG")
+        .ok()
+        .and_then(|var| var.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(Level::INFO));
+    let layer = tracing_subscriber::fmt::layer();
+    match format {
+        LogFormat::Full => tracing_subscriber::registry()
+            .with(layer)
+            .with(targets)
+            .init(),
+        LogFormat::Compact => tracing_subscriber::registry()
+            .with(layer.compact())
+            .with(targets)
+            .init(),
+    }
+}
+
+/// Waits for a watched file to change, or forever when not watching
+async fn changed(watcher: Option<&mut FileWatcher>) -> io::Result<()> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever without one
+async fn tick(interval: Option<&mut Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleeps until `deadline`, or forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn fire(
+    rule: &Rule,
+    event: &UdevEvent,
+    executor: &Executor,
+    rule_fired_tx: &broadcast::Sender<RuleFired>,
+    cause: u64,
+) {
+    executor.authorize(rule, &event.port, cause);
+    executor.spawn(rule, event, cause);
+    // Only fails when nobody is subscribed
+    let _ = rule_fired_tx.send(RuleFired {
+        rule: rule.name.clone(),
+        trigger: Trigger::Event(event.clone()),
+    });
+}
+
+/// Carries out the rules triggered by `event` from the daemon itself
+///
+/// At startup and after a reload, what's attached is authorized according to
+/// the policy and rules first.
+fn fire_lifecycle(
+    state: &State,
+    event: UsbEvent,
+    executor: &Executor,
+    rule_fired_tx: &broadcast::Sender<RuleFired>,
+) {
+    let triggered = state.lifecycle(event);
+    let mut matched: Vec<_> = triggered.iter().map(|(r, _)| r.name.clone()).collect();
+    matched.dedup();
+    let cause = executor.audit().record(Entry::Lifecycle {
+        event,
+        evaluated: state
+            .rules
+            .iter()
+            .filter(|r| r.on() == Some(event))
+            .map(|r| r.name.clone())
+            .collect(),
+        matched,
+    });
+
+    if event != UsbEvent::Shutdown {
+        for (port, authorize, rule) in state.authorizations() {
+            executor.enforce(&port, authorize, rule, cause);
+        }
+    }
+    for (rule, lifecycle) in triggered {
+        info!(rule = ?rule.name, %event, "Found matching rule");
+        let attached = lifecycle.attached.iter();
+        for a in attached.chain(lifecycle.devices.iter().flatten()) {
+            executor.authorize(&rule, &a.port, cause);
+        }
+        executor.spawn(&rule, &lifecycle, cause);
+        let _ = rule_fired_tx.send(RuleFired {
+            rule: rule.name.clone(),
+            trigger: Trigger::Lifecycle(lifecycle),
+        });
+    }
+}
+
+/// Looks for the child nodes of a newly added device until they match `rule`,
+/// or the rule's wait runs out
+async fn wait_for_nodes(
+    rule: Rule,
+    mut event: UdevEvent,
+    executor: Executor,
+    rule_fired_tx: broadcast::Sender<RuleFired>,
+    cause: u64,
+) {
+    let Some(syspath) = event.port.syspath().map(PathBuf::from) else {
+        return;
+    };
+    let deadline = Instant::now() + rule.wait();
+
+    loop {
+        match DeviceNode::children_of(&syspath, &rule.node_subsystems()) {
+            Ok(nodes) => event.nodes = nodes,
+            Err(err) => {
+                debug!(rule = ?rule.name, cause = %err, "Device gone; no longer waiting for nodes");
+                return;
+            }
+        }
+        if rule.matches_udev_event(&event) {
+            info!(rule = ?rule.name, "Found matching rule");
+            let cause = executor.audit().record(Entry::Nodes {
+                cause,
+                rule: rule.name.clone(),
+            });
+            fire(&rule, &event, &executor, &rule_fired_tx, cause);
+            return;
+        }
+        if Instant::now() >= deadline {
+            info!(rule = ?rule.name, "Gave up waiting for device nodes");
+            return;
+        }
+        tokio::time::sleep(NODE_POLL).await;
     }
-    Ok(())
 }
 
 impl Handler {
@@ -183,9 +671,33 @@ impl Handler {
         let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
         tokio::pin!(shutdown);
 
+        // Startup rules and state rules see whatever is already plugged in
+        fire_lifecycle(
+            &self.state.lock(),
+            UsbEvent::Startup,
+            &self.executor,
+            &self.rule_fired_tx,
+        );
+        self.check_state();
+
         while !shutdown.is_shutdown() {
+            let deadline = self.state.lock().next_deadline();
             let event = tokio::select! {
                 res = self.udev_event_rx.recv() => res?, // @TODO: add real error
+                _ = sleep_until(deadline) => {
+                    self.check_state();
+                    continue;
+                }
+                _ = self.reloaded.notified() => {
+                    fire_lifecycle(
+                        &self.state.lock(),
+                        UsbEvent::Reload,
+                        &self.executor,
+                        &self.rule_fired_tx,
+                    );
+                    self.check_state();
+                    continue;
+                }
                 _ = shutdown.recv() => {
                     info!("Shutting down handler");
                     return Ok(());
@@ -201,22 +713,115 @@ impl Handler {
                 if event.event_kind == UsbEvent::Add {
                     debug!("Adding");
                     s.add_and_slot_device(event.device.clone(), event.port.clone());
+                    s.attach(event.port.clone(), event.device.clone());
                 } else if event.event_kind == UsbEvent::Remove {
                     debug!("Removing");
                     s.rm_and_unslot_device(event.device.clone());
+                    s.detach(&event.port);
+                }
+                if let Some(notifier) = &self.notifier {
+                    if matches!(event.event_kind, UsbEvent::Add | UsbEvent::Remove) {
+                        notifier.status(&s.status());
+                    }
                 }
 
+                let mut evaluated = Vec::new();
+                let mut matched = Vec::new();
+                let mut waiting = Vec::new();
                 for r in &s.rules {
-                    if r.matches_udev_event(&event) {
-                        info!(rule = ?r.name, "Found matching rule");
-                        let cmd = r.command.clone();
-                        let shell = r.command_shell.clone();
-                        tokio::spawn(exec(cmd, shell));
+                    if !r.on().is_some_and(|on| !on.is_lifecycle()) {
+                        continue;
                     }
+                    evaluated.push(r.name.clone());
+                    if r.needs_nodes() {
+                        if event.event_kind == UsbEvent::Add && r.matches_device_event(&event) {
+                            waiting.push(r);
+                        }
+                    } else if r.matches_udev_event(&event) {
+                        matched.push(r);
+                    }
+                }
+                let policy = (event.event_kind == UsbEvent::Add && !event.port.is_root_hub())
+                    .then(|| s.authorization(&event.device))
+                    .flatten();
+                let cause = self.executor.audit().record(Entry::Event {
+                    udev: Box::new(event.clone()),
+                    evaluated,
+                    matched: matched.iter().map(|r| r.name.clone()).collect(),
+                    waiting: waiting.iter().map(|r| r.name.clone()).collect(),
+                    policy,
+                });
+
+                // The policy goes first so rules get the last word
+                if let Some(authorize) = policy {
+                    info!(device = %event.device, %authorize, "Applying policy");
+                    self.executor.enforce(&event.port, authorize, None, cause);
+                }
+                for r in matched {
+                    info!(rule = ?r.name, "Found matching rule");
+                    fire(r, &event, &self.executor, &self.rule_fired_tx, cause);
+                }
+                for r in waiting {
+                    info!(rule = ?r.name, "Waiting for device nodes");
+                    tokio::spawn(wait_for_nodes(
+                        r.clone(),
+                        event.clone(),
+                        self.executor.clone(),
+                        self.rule_fired_tx.clone(),
+                        cause,
+                    ));
                 }
             }
+            self.check_state();
         }
 
         Ok(())
     }
+
+    /// Runs the command of each state rule whose condition just changed
+    fn check_state(&self) {
+        let changes = self.state.lock().evaluate(Instant::now());
+        for (rule, change) in changes {
+            info!(rule = ?rule.name, active = ?change.active, "State rule changed");
+            let cause = self.executor.audit().record(Entry::State {
+                rule: rule.name.clone(),
+                active: change.active,
+                devices: change.devices.clone(),
+            });
+            self.executor.spawn(&rule, &change, cause);
+            let _ = self.rule_fired_tx.send(RuleFired {
+                rule: rule.name.clone(),
+                trigger: Trigger::State(change),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        run: UsbWatchRun,
+    }
+
+    fn run_with(args: &[&str], config: &str) -> UsbWatchRun {
+        let cli = Cli::parse_from([&["usbwatch"], args].concat());
+        cli.run.with_config(&config.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn watch_precedence() {
+        let on = "run:\n  rules: [rules.yml]\n  watch: true\n";
+        let unset = "run:\n  rules: [rules.yml]\n";
+
+        assert!(run_with(&[], on).watch());
+        assert!(!run_with(&["--watch=false"], on).watch());
+        assert!(run_with(&["--watch"], unset).watch());
+        assert!(!run_with(&[], unset).watch());
+    }
 }
@@ -6,7 +6,7 @@ use tokio_udev::Enumerator;
 use crate::{
     cli::{Cmd, ForObject},
     ctx::Ctx,
-    printer::OutFormat,
+    printer::{print_doc, print_tree, Entry, OutFormat, Table},
     usb::{UsbDevice, UsbPort},
 };
 
@@ -14,6 +14,8 @@ use crate::{
 #[derive(Args, Copy, Clone, Debug)]
 pub struct UsbWatchScan {
     /// Only display KIND of objects
+    ///
+    /// The tree format always displays both ports and devices.
     #[arg(
         long,
         short,
@@ -26,67 +28,52 @@ pub struct UsbWatchScan {
 
 impl Cmd for UsbWatchScan {
     fn run(&self, ctx: &mut Ctx) -> anyhow::Result<()> {
-        let mut scanner = Enumerator::new().unwrap();
-        scanner.match_subsystem("usb").unwrap();
-
-        let mut ports = Vec::new();
-        let mut devices = Vec::new();
-        for dev in scanner
-            .scan_devices()
-            .unwrap()
-            .filter(|d| Some(OsStr::new("usb_interface")) != d.devtype())
-        {
-            let usbdev = UsbDevice::from(&dev);
-            let usbport = UsbPort::from(&dev);
-            if !usbdev.is_empty() {
-                devices.push(usbdev);
-            }
-            if !usbport.is_empty() {
-                ports.push(usbport);
-            }
-        }
+        let (ports, devices): (Vec<_>, Vec<_>) = attached()?.into_iter().unzip();
 
         match ctx.format {
-            OutFormat::Raw => {
-                if self.only == ForObject::Ports || self.only == ForObject::All {
-                    cli_println!("{:#?}", ports);
-                }
-                if self.only == ForObject::Devices || self.only == ForObject::All {
-                    cli_println!("{:#?}", devices);
+            OutFormat::Table | OutFormat::Tree => {
+                let entries: Vec<_> = ports
+                    .iter()
+                    .zip(devices.iter())
+                    .map(|(port, device)| Entry {
+                        port,
+                        device,
+                        event: None,
+                    })
+                    .collect();
+                if ctx.format == OutFormat::Tree {
+                    print_tree(&entries);
+                } else {
+                    Table::new(self.only, false).print(&entries);
                 }
             }
-            OutFormat::Yaml => {
-                if self.only == ForObject::Ports || self.only == ForObject::All {
-                    cli_println!("---\nports:");
-                    for port in ports {
-                        print!("  - ");
-                        let yaml = serde_yaml::to_string(&port).unwrap();
-                        for (i, line) in yaml.lines().skip(1).enumerate() {
-                            if i == 0 {
-                                cli_println!("{}", line);
-                            } else {
-                                cli_println!("    {}", line);
-                            }
-                        }
-                    }
+            format => {
+                // Output is usually saved as an inventory to match against
+                // later
+                let mut inventory = self.only.inventory(ports, devices);
+                inventory.strip_volatile();
+                if let Some(ports) = inventory.ports.as_mut() {
+                    ports.retain(|p| !p.is_empty());
                 }
-                if self.only == ForObject::Devices || self.only == ForObject::All {
-                    cli_println!("---\ndevices:");
-                    for device in devices {
-                        print!("  - ");
-                        let yaml = serde_yaml::to_string(&device).unwrap();
-                        for (i, line) in yaml.lines().skip(1).enumerate() {
-                            if i == 0 {
-                                cli_println!("{}", line);
-                            } else {
-                                cli_println!("    {}", line);
-                            }
-                        }
-                    }
+                if let Some(devices) = inventory.devices.as_mut() {
+                    devices.retain(|d| !d.is_empty());
                 }
+                print_doc(&inventory, format)?;
             }
         }
 
         Ok(())
     }
 }
+
+/// Every USB device currently attached, along with the port it's attached to
+pub fn attached() -> anyhow::Result<Vec<(UsbPort, UsbDevice)>> {
+    let mut scanner = Enumerator::new()?;
+    scanner.match_subsystem("usb")?;
+
+    Ok(scanner
+        .scan_devices()?
+        .filter(|d| Some(OsStr::new("usb_interface")) != d.devtype())
+        .map(|dev| (UsbPort::from(&dev), UsbDevice::from(&dev)))
+        .collect())
+}
@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::{audit, cli::Cmd, ctx::Ctx};
+
+/// Check that an audit log hasn't been tampered with
+///
+/// Only works for audit logs written with `hash_chain: true`.
+#[derive(Args, Debug)]
+pub struct UsbWatchVerifyAudit {
+    /// Audit log files, oldest first, i.e. `audit.log.2 audit.log.1
+    /// audit.log`
+    ///
+    /// Every record has to follow the one before it across all of the files.
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+}
+
+impl Cmd for UsbWatchVerifyAudit {
+    fn run(&self, _ctx: &mut Ctx) -> anyhow::Result<()> {
+        let count = audit::verify(&self.paths)?;
+        cli_println!("{count} records verified");
+        Ok(())
+    }
+}
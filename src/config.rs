@@ -0,0 +1,268 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use strum::Display;
+
+use crate::authorize::Policy;
+
+/// Where the config file is read from when `--config` isn't given
+pub const DEFAULT_CONFIG: &str = "/etc/usbwatch/config.yml";
+
+/// The shell commands are run with when neither the rule nor the config file
+/// says otherwise
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Where sysfs is mounted, which device authorization is written to
+pub const DEFAULT_SYSFS: &str = "/sys";
+
+/// Seconds commands get to finish when shutting down
+pub const DEFAULT_SHUTDOWN_GRACE: u64 = 10;
+
+/// How many rotated audit logs are kept
+pub const DEFAULT_AUDIT_KEEP: usize = 10;
+
+/// Size of the queues events and fired rules wait in
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 32; // 32 picked by fair diceroll
+
+/// Defaults for `usbwatch run` and the commands it runs
+///
+/// Anything given on the command line or in the environment takes precedence
+/// over what's here, and what's here over the built in defaults.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub run: RunConfig,
+    pub exec: ExecConfig,
+    pub authorize: AuthorizeConfig,
+    pub audit: AuditConfig,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    /// Rules files or directories, like `--rules`
+    pub rules: Vec<PathBuf>,
+    pub devices: Option<PathBuf>,
+    pub ports: Option<PathBuf>,
+    pub socket: Option<PathBuf>,
+    pub watch: Option<bool>,
+    /// Used when neither `-v` nor `RUST_LOG` are given
+    pub log_level: Option<Verbosity>,
+    pub log_format: LogFormat,
+    /// Size of the queues events and fired rules wait in
+    pub channel_capacity: Option<usize>,
+    /// Seconds commands get to finish when shutting down, like
+    /// `--shutdown-grace`
+    pub shutdown_grace: Option<u64>,
+}
+
+/// How rule commands are run
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecConfig {
+    /// Shell for rules without a `command_shell`
+    pub shell: Option<PathBuf>,
+    /// Extra environment variables for every command
+    pub env: BTreeMap<String, String>,
+    /// Seconds a command may run before it's killed
+    pub timeout: Option<u64>,
+    /// How many commands may run at once; the rest wait their turn
+    pub concurrency: Option<usize>,
+    /// What happens to commands still waiting for their turn when shutting
+    /// down
+    pub shutdown_queue: ShutdownQueue,
+}
+
+/// Which devices may be used
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthorizeConfig {
+    /// Like `--policy`
+    pub policy: Option<Policy>,
+    /// Devices files of the devices `default-deny` allows, like `--allow`
+    pub allow: Vec<PathBuf>,
+    /// Where sysfs is mounted; only ever changed for testing
+    pub sysfs: Option<PathBuf>,
+}
+
+/// Where and how the audit trail is kept
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSON lines file records are appended to, like `--audit-log`
+    pub path: Option<PathBuf>,
+    /// Bytes the file may grow to before it's rotated
+    pub max_size: Option<u64>,
+    /// Seconds the file is written to before it's rotated
+    pub max_age: Option<u64>,
+    /// How many rotated files are kept, as PATH.1 (the newest) to PATH.N
+    pub keep: Option<usize>,
+    /// Chain each record to the one before it with a SHA-256 hash, so
+    /// changing or removing records can be detected
+    pub hash_chain: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownQueue {
+    /// Run them, as long as they're done within the grace period
+    #[default]
+    Drain,
+    /// Never run them
+    Discard,
+}
+
+#[derive(Deserialize, Display, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Verbosity {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    /// Fields on the same line as the message, without span names
+    Compact,
+}
+
+impl Config {
+    /// Reads the config file at `path`, or the [`DEFAULT_CONFIG`] if there is
+    /// one when no path is given
+    ///
+    /// Relative paths in the file are relative to the directory the file is
+    /// in.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(DEFAULT_CONFIG), false),
+        };
+        let buf = match fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+
+        let mut config: Self = buf
+            .parse()
+            .with_context(|| format!("invalid config in {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let run = &mut self.run;
+        for path in run
+            .rules
+            .iter_mut()
+            .chain(&mut run.devices)
+            .chain(&mut run.ports)
+            .chain(&mut run.socket)
+            .chain(&mut self.authorize.allow)
+            .chain(&mut self.authorize.sysfs)
+            .chain(&mut self.audit.path)
+        {
+            *path = dir.join(&*path);
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(buf: &str) -> anyhow::Result<Self> {
+        if buf.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let config: Self = serde_yaml::from_str(buf)?;
+        if config.run.channel_capacity == Some(0) {
+            bail!("run 'channel_capacity' must be at least 1");
+        }
+        if config.exec.concurrency == Some(0) {
+            bail!("exec 'concurrency' must be at least 1");
+        }
+        if config.audit.keep == Some(0) {
+            bail!("audit 'keep' must be at least 1");
+        }
+        Ok(config)
+    }
+}
+
+impl RunConfig {
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY)
+    }
+}
+
+impl AuthorizeConfig {
+    pub fn sysfs(&self) -> PathBuf { self.sysfs.clone().unwrap_or_else(|| DEFAULT_SYSFS.into()) }
+}
+
+impl AuditConfig {
+    pub fn max_age(&self) -> Option<Duration> { self.max_age.map(Duration::from_secs) }
+
+    pub fn keep(&self) -> usize { self.keep.unwrap_or(DEFAULT_AUDIT_KEEP) }
+}
+
+impl ExecConfig {
+    pub fn shell(&self) -> PathBuf { self.shell.clone().unwrap_or_else(|| DEFAULT_SHELL.into()) }
+
+    pub fn timeout(&self) -> Option<Duration> { self.timeout.map(Duration::from_secs) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_parse() {
+        let config: Config = "run:\n  rules: [rules.yml, /etc/usbwatch/rules.d]\n  log_level: debug\n  log_format: compact\nexec:\n  shell: /bin/bash\n  env: {KIOSK: front}\n  timeout: 30\n"
+            .parse()
+            .unwrap();
+        assert_eq!(config.run.log_level, Some(Verbosity::Debug));
+        assert_eq!(config.run.log_format, LogFormat::Compact);
+        assert_eq!(config.run.channel_capacity(), DEFAULT_CHANNEL_CAPACITY);
+        assert_eq!(config.exec.shell(), Path::new("/bin/bash"));
+        assert_eq!(config.exec.env["KIOSK"], "front");
+        assert_eq!(config.exec.timeout(), Some(Duration::from_secs(30)));
+
+        let mut resolved = config.clone();
+        resolved.resolve_paths(Path::new("/etc/usbwatch"));
+        assert_eq!(
+            resolved.run.rules,
+            [
+                Path::new("/etc/usbwatch/rules.yml"),
+                Path::new("/etc/usbwatch/rules.d")
+            ]
+        );
+
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert!("run:\n  rule: rules.yml\n".parse::<Config>().is_err());
+        assert!("exec:\n  concurrency: 0\n".parse::<Config>().is_err());
+
+        let config: Config = "authorize:\n  policy: default-deny\n  allow: [allowed.yml]\n"
+            .parse()
+            .unwrap();
+        assert_eq!(config.authorize.policy, Some(Policy::DefaultDeny));
+        assert_eq!(config.authorize.sysfs(), Path::new(DEFAULT_SYSFS));
+        assert!("authorize:\n  policy: deny\n".parse::<Config>().is_err());
+        assert!("audit:\n  keep: 0\n".parse::<Config>().is_err());
+    }
+}
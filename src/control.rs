@@ -0,0 +1,350 @@
+use std::{
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+};
+use tracing::{debug, error, info, span, warn, Level};
+
+use crate::{
+    rule::{Lifecycle, StateChange},
+    shutdown::Shutdown,
+    udev::UdevEvent,
+    usb::UsbEvent,
+};
+
+/// Where `run` listens, and `ctl` connects, when no `--socket` is given
+pub const DEFAULT_SOCKET: &str = "/run/usbwatch.sock";
+
+/// A request sent by a `ctl` client as a single line of JSON
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    Subscribe(Subscription),
+}
+
+/// Which notifications a subscriber is interested in
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Subscription {
+    /// Only forward firings of the rule with this name
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rule: Option<String>,
+    /// Only forward events (and firings caused by events) of this kind
+    #[serde(default)]
+    pub event: UsbEvent,
+}
+
+impl Subscription {
+    pub fn wants(&self, notification: &Notification) -> bool {
+        let (rule, kind) = match notification {
+            Notification::Event(event) => (None, Some(event.event_kind)),
+            Notification::RuleFired(fired) => (Some(&fired.rule), fired.trigger.event_kind()),
+        };
+
+        let rule_ok = match (&self.rule, rule) {
+            (Some(want), Some(got)) => want == got,
+            // Asking for a particular rule excludes the raw event stream
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        // State rules aren't fired by any kind of event
+        rule_ok && (self.event == UsbEvent::All || Some(self.event) == kind)
+    }
+}
+
+/// A rule fired and its actions were started
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RuleFired {
+    pub rule: String,
+    #[serde(flatten)]
+    pub trigger: Trigger,
+}
+
+/// What fired a rule, under a key naming which kind of thing it was (i.e.
+/// `event`)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// A udev event, for `match` rules
+    Event(UdevEvent),
+    /// The condition of a state rule changing
+    State(StateChange),
+    /// The daemon starting, reloading or shutting down
+    Lifecycle(Lifecycle),
+}
+
+impl Trigger {
+    /// The kind of event behind the trigger, if any
+    fn event_kind(&self) -> Option<UsbEvent> {
+        match self {
+            Trigger::Event(event) => Some(event.event_kind),
+            Trigger::State(_) => None,
+            Trigger::Lifecycle(lifecycle) => Some(lifecycle.event),
+        }
+    }
+}
+
+/// A message streamed from the daemon to a subscriber as a single line of
+/// JSON
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Notification {
+    Event(UdevEvent),
+    RuleFired(RuleFired),
+}
+
+/// Control socket state
+#[derive(Debug)]
+pub struct ControlListener {
+    pub socket: PathBuf,
+    /// The same channel the udev listener broadcasts on
+    pub udev_event_tx: broadcast::Sender<UdevEvent>,
+    pub rule_fired_tx: broadcast::Sender<RuleFired>,
+    pub shutdown: Shutdown,
+    pub shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl ControlListener {
+    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let span = span!(Level::TRACE, "fn run", socket = ?self.socket);
+        let _enter = span.enter();
+
+        let listener = bind(&self.socket)?;
+        info!(socket = ?self.socket, "Listening for control connections");
+
+        while !self.shutdown.is_shutdown() {
+            let stream = tokio::select! {
+                res = listener.accept() => res?.0,
+                _ = self.shutdown.recv() => break,
+            };
+            debug!("Accepted control connection");
+
+            // Connections end on their own once the event channels close
+            let conn = Connection {
+                stream,
+                udev_event_rx: self.udev_event_tx.subscribe(),
+                rule_fired_rx: self.rule_fired_tx.subscribe(),
+            };
+            tokio::spawn(async move {
+                if let Err(err) = conn.run().await {
+                    error!(cause = %err, "control connection failed");
+                }
+            });
+        }
+
+        let _ = fs::remove_file(&self.socket);
+        Ok(())
+    }
+}
+
+/// Listens on `path`, replacing a socket left behind by an unclean exit
+///
+/// Anything else at `path` is left alone: a file that isn't a socket may well
+/// be a mistyped path, and a socket that still accepts connections belongs to
+/// another daemon.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists and isn't a socket", path.display()),
+            ));
+        }
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already in use", path.display()),
+            ));
+        }
+        Ok(_) => {
+            debug!(socket = ?path, "Removing stale control socket");
+            fs::remove_file(path)?;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    UnixListener::bind(path)
+}
+
+struct Connection {
+    stream: UnixStream,
+    udev_event_rx: broadcast::Receiver<UdevEvent>,
+    rule_fired_rx: broadcast::Receiver<RuleFired>,
+}
+
+impl Connection {
+    async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (rd, mut wr) = self.stream.split();
+        let mut lines = BufReader::new(rd).lines();
+
+        let Some(line) = lines.next_line().await? else {
+            return Ok(());
+        };
+        let Request::Subscribe(sub) = serde_json::from_str(&line)?;
+        debug!(?sub, "New subscriber");
+
+        loop {
+            let notification = tokio::select! {
+                res = self.udev_event_rx.recv() => match res {
+                    Ok(event) => Notification::Event(event),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(skipped = %n, "Subscriber lagging; dropped events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                res = self.rule_fired_rx.recv() => match res {
+                    Ok(fired) => Notification::RuleFired(fired),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(skipped = %n, "Subscriber lagging; dropped rule firings");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                // The client hung up
+                res = lines.next_line() => match res? {
+                    Some(_) => continue,
+                    None => return Ok(()),
+                },
+            };
+
+            if !sub.wants(&notification) {
+                continue;
+            }
+
+            let mut buf = serde_json::to_vec(&notification)?;
+            buf.push(b'\n');
+            wr.write_all(&buf).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::TempDir,
+        usb::{UsbDevice, UsbPort},
+    };
+
+    fn event(kind: UsbEvent) -> UdevEvent {
+        UdevEvent {
+            event_kind: kind,
+            device: UsbDevice::new("foo"),
+            port: UsbPort::new("bar"),
+            nodes: Default::default(),
+        }
+    }
+
+    fn fired(rule: &str, kind: UsbEvent) -> Notification {
+        Notification::RuleFired(RuleFired {
+            rule: rule.into(),
+            trigger: Trigger::Event(event(kind)),
+        })
+    }
+
+    fn state_fired(rule: &str) -> Notification {
+        Notification::RuleFired(RuleFired {
+            rule: rule.into(),
+            trigger: Trigger::State(StateChange {
+                rule: rule.into(),
+                active: true,
+                devices: Vec::new(),
+            }),
+        })
+    }
+
+    #[tokio::test]
+    async fn bind_only_replaces_stale_sockets() {
+        let dir = TempDir::new("control");
+        let socket = dir.join("usbwatch.sock");
+
+        // Left behind by a daemon that's gone
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        let running = bind(&socket).unwrap();
+        let err = bind(&socket).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(running);
+
+        let config = dir.join("config.yml");
+        fs::write(&config, "run: {}\n").unwrap();
+        let err = bind(&config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&config).unwrap(), "run: {}\n");
+    }
+
+    #[test]
+    fn subscription_default_wants_everything() {
+        let sub = Subscription::default();
+
+        assert!(sub.wants(&Notification::Event(event(UsbEvent::Add))));
+        assert!(sub.wants(&Notification::Event(event(UsbEvent::Remove))));
+        assert!(sub.wants(&fired("foo", UsbEvent::Add)));
+        assert!(sub.wants(&state_fired("foo")));
+    }
+
+    #[test]
+    fn subscription_event() {
+        let sub = Subscription {
+            event: UsbEvent::Add,
+            ..Default::default()
+        };
+
+        assert!(sub.wants(&Notification::Event(event(UsbEvent::Add))));
+        assert!(!sub.wants(&Notification::Event(event(UsbEvent::Remove))));
+        assert!(sub.wants(&fired("foo", UsbEvent::Add)));
+        assert!(!sub.wants(&fired("foo", UsbEvent::Remove)));
+        assert!(!sub.wants(&fired("foo", UsbEvent::Startup)));
+        assert!(!sub.wants(&state_fired("foo")));
+    }
+
+    #[test]
+    fn subscription_rule() {
+        let sub = Subscription {
+            rule: Some("foo".into()),
+            ..Default::default()
+        };
+
+        assert!(!sub.wants(&Notification::Event(event(UsbEvent::Add))));
+        assert!(sub.wants(&fired("foo", UsbEvent::Add)));
+        assert!(!sub.wants(&fired("bar", UsbEvent::Add)));
+        assert!(sub.wants(&state_fired("foo")));
+    }
+
+    #[test]
+    fn notification_round_trip() {
+        let json = serde_json::to_string(&fired("foo", UsbEvent::Add)).unwrap();
+        assert!(json.starts_with(r#"{"kind":"rule_fired","rule":"foo","event":{"event":"add""#));
+        assert_eq!(
+            serde_json::from_str::<Notification>(&json).unwrap(),
+            fired("foo", UsbEvent::Add)
+        );
+
+        let json = serde_json::to_string(&state_fired("foo")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Notification>(&json).unwrap(),
+            state_fired("foo")
+        );
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let req = Request::Subscribe(Subscription {
+            rule: Some("foo".into()),
+            event: UsbEvent::Add,
+        });
+        let json = serde_json::to_string(&req).unwrap();
+
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), req);
+    }
+}
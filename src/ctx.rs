@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use crate::printer::{ColorChoice, OutFormat};
 
 #[derive(Default)]
@@ -8,4 +10,6 @@ pub struct Ctx {
     pub format: OutFormat,
     pub color: ColorChoice,
     pub num_events: usize,
+    // Control socket of a running daemon
+    pub socket: Option<PathBuf>,
 }
@@ -0,0 +1,417 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::PathBuf,
+    process::{self, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{process::Command, sync::Semaphore, task::JoinSet, time::timeout};
+use tracing::{debug, info, span, warn, Instrument, Level};
+
+use crate::{
+    audit::{Audit, Entry, Outcome},
+    authorize::{Authorize, Sysfs},
+    config::{ExecConfig, ShutdownQueue},
+    rule::Rule,
+    usb::UsbPort,
+};
+
+/// How long commands get to exit after being asked to at the end of the
+/// shutdown grace period, before they're killed
+const KILL_AFTER: Duration = Duration::from_secs(2);
+
+/// Carries out the actions of rules: authorizing devices, and running
+/// commands the way the config file says to
+///
+/// Each command runs in its own process group so that anything it starts is
+/// stopped along with it.
+#[derive(Clone)]
+pub struct Executor {
+    config: Arc<ExecConfig>,
+    sysfs: Sysfs,
+    /// Told about every action, and how it went
+    audit: Audit,
+    /// Taken by each running command when only so many may run at once
+    permits: Option<Arc<Semaphore>>,
+    /// Every command that's running or waiting for its turn
+    tasks: Arc<Mutex<JoinSet<()>>>,
+    /// Process groups of the commands running right now, and what they are
+    running: Arc<Mutex<HashMap<i32, (Run, Instant)>>>,
+    /// Set when shutting down, after which no new commands are started
+    closed: Arc<AtomicBool>,
+}
+
+impl Executor {
+    pub fn new(config: ExecConfig, sysfs: Sysfs, audit: Audit) -> Self {
+        Self {
+            sysfs,
+            audit,
+            permits: config.concurrency.map(|n| Arc::new(Semaphore::new(n))),
+            config: Arc::new(config),
+            tasks: Default::default(),
+            running: Default::default(),
+            closed: Default::default(),
+        }
+    }
+
+    pub fn audit(&self) -> &Audit { &self.audit }
+
+    /// Authorizes or deauthorizes the device plugged into `port`, if `rule`
+    /// says to, because of the audit record `cause`
+    ///
+    /// Unlike commands this happens right away, so it's done before anything
+    /// else gets to use the device.
+    pub fn authorize(&self, rule: &Rule, port: &UsbPort, cause: u64) {
+        if let Some(authorize) = rule.authorize {
+            info!(rule = ?rule.name, %authorize, "Authorizing device");
+            self.write_authorization(Some(&rule.name), port, authorize, cause);
+        }
+    }
+
+    /// Authorizes or deauthorizes the device plugged into `port` the way the
+    /// policy, or `rule` when it overrides the policy, says to
+    pub fn enforce(&self, port: &UsbPort, authorize: Authorize, rule: Option<&str>, cause: u64) {
+        debug!(port = %port, %authorize, ?rule, "Enforcing authorization");
+        self.write_authorization(rule, port, authorize, cause);
+    }
+
+    fn write_authorization(
+        &self,
+        rule: Option<&str>,
+        port: &UsbPort,
+        authorize: Authorize,
+        cause: u64,
+    ) {
+        let error = self.sysfs.authorize(port, authorize).err();
+        if let Some(err) = &error {
+            warn!(cause = %err, port = %port, %authorize, "Failed to authorize device");
+        }
+        self.audit.record(Entry::Authorize {
+            cause,
+            rule: rule.map(String::from),
+            syspath: port.syspath().map(String::from),
+            authorize,
+            error: error.map(|e| e.to_string()),
+        });
+    }
+
+    /// Runs `rule`'s command, filled in from `value`, in the background
+    /// because of the audit record `cause`
+    pub fn spawn<T: Serialize>(&self, rule: &Rule, value: &T, cause: u64) {
+        let Some(cmd) = rule.command_for(value) else {
+            return;
+        };
+        let run = Run {
+            cause,
+            rule: rule.name.clone(),
+            cmd,
+        };
+        if self.closed.load(Ordering::Relaxed) {
+            info!(rule = ?rule.name, "Shutting down; not running command");
+            self.finished(run, Outcome::Discarded, None, None, Instant::now());
+            return;
+        }
+
+        let shell = rule
+            .command_shell
+            .clone()
+            .unwrap_or_else(|| self.config.shell());
+        let mut tasks = self.tasks.lock();
+        // Forget the commands which have already finished
+        while tasks.try_join_next().is_some() {}
+        let span = span!(Level::TRACE, "fn exec", rule = ?rule.name);
+        tasks.spawn(self.clone().exec(run, shell).instrument(span));
+    }
+
+    async fn exec(self, run: Run, shell: PathBuf) {
+        let _permit = match &self.permits {
+            Some(permits) => {
+                debug!("Waiting for a free slot");
+                match permits.acquire().await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        info!("Shutting down; discarding queued command");
+                        self.finished(run, Outcome::Discarded, None, None, Instant::now());
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        debug!("Executing command");
+        let started = Instant::now();
+        let mut command = process::Command::new(&shell);
+        command.process_group(0);
+        let spawned = Command::from(command)
+            .arg("-c")
+            .arg(&run.cmd)
+            .envs(&self.config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                warn!(cause = %err, shell = ?shell, "Failed to run command");
+                let error = Some(err.to_string());
+                self.finished(run, Outcome::Failed, None, error, started);
+                return;
+            }
+        };
+        let pgid = child.id().map(|id| id as i32);
+        if let Some(pgid) = pgid {
+            self.running.lock().insert(pgid, (run.clone(), started));
+        }
+
+        info!("Executing command");
+        debug!("Waiting for child to exit");
+        let status = match self.config.timeout() {
+            Some(limit) => match timeout(limit, child.wait()).await {
+                Ok(status) => Some(status),
+                Err(_) => {
+                    info!(timeout = ?limit, "Command timed out; killing it");
+                    if let Some(pgid) = pgid {
+                        signal_group(pgid, libc::SIGKILL);
+                    }
+                    None
+                }
+            },
+            None => Some(child.wait().await),
+        };
+        if let Some(pgid) = pgid {
+            self.running.lock().remove(&pgid);
+        }
+
+        let status = match status {
+            Some(Ok(status)) => status,
+            Some(Err(err)) => {
+                warn!(cause = %err, "Failed to wait for command");
+                let error = Some(err.to_string());
+                self.finished(run, Outcome::Failed, None, error, started);
+                return;
+            }
+            None => {
+                self.finished(run, Outcome::TimedOut, None, None, started);
+                return;
+            }
+        };
+        if status.success() {
+            info!("Command completed successfully");
+        } else {
+            info!(
+                "Command completed with error code {code:?}",
+                code = status.code()
+            );
+        }
+        self.finished(run, Outcome::Finished, Some(status), None, started);
+    }
+
+    fn finished(
+        &self,
+        run: Run,
+        outcome: Outcome,
+        status: Option<ExitStatus>,
+        error: Option<String>,
+        started: Instant,
+    ) {
+        self.audit.record(Entry::Command {
+            cause: run.cause,
+            rule: run.rule,
+            command: run.cmd,
+            outcome,
+            code: status.and_then(|s| s.code()),
+            signal: status.and_then(|s| s.signal()),
+            error,
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+    }
+
+    /// Stops starting new commands and gives those already started up to
+    /// `grace` to finish, before asking them to exit and finally killing them
+    ///
+    /// Commands still waiting for their turn are run within `grace` or
+    /// discarded, depending on `shutdown_queue`.
+    pub async fn shutdown(&self, grace: Duration) {
+        self.closed.store(true, Ordering::Relaxed);
+        if self.config.shutdown_queue == ShutdownQueue::Discard {
+            self.close_queue();
+        }
+
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+        while tasks.try_join_next().is_some() {}
+        if tasks.is_empty() {
+            return;
+        }
+
+        info!(commands = %tasks.len(), grace = ?grace, "Waiting for commands to finish");
+        if timeout(grace, join_all(&mut tasks)).await.is_ok() {
+            return;
+        }
+
+        // Anything still queued won't get a turn now
+        self.close_queue();
+        warn!(commands = %tasks.len(), "Commands still running after the grace period; terminating them");
+        self.signal_all(libc::SIGTERM);
+        if timeout(KILL_AFTER, join_all(&mut tasks)).await.is_ok() {
+            return;
+        }
+
+        warn!(commands = %tasks.len(), "Commands ignored SIGTERM; killing them");
+        self.signal_all(libc::SIGKILL);
+        // Killed commands are normally reaped (and recorded) right away
+        if timeout(KILL_AFTER, join_all(&mut tasks)).await.is_ok() {
+            return;
+        }
+
+        tasks.abort_all();
+        join_all(&mut tasks).await;
+        let abandoned: Vec<_> = self.running.lock().drain().collect();
+        for (_, (run, started)) in abandoned {
+            self.finished(run, Outcome::Killed, None, None, started);
+        }
+    }
+
+    fn close_queue(&self) {
+        if let Some(permits) = &self.permits {
+            permits.close();
+        }
+    }
+
+    fn signal_all(&self, signal: libc::c_int) {
+        for pgid in self.running.lock().keys() {
+            signal_group(*pgid, signal);
+        }
+    }
+}
+
+/// A command to run, and what it's for
+#[derive(Clone)]
+struct Run {
+    /// The audit record of whatever fired the rule
+    cause: u64,
+    rule: String,
+    cmd: String,
+}
+
+async fn join_all(tasks: &mut JoinSet<()>) { while tasks.join_next().await.is_some() {} }
+
+/// Sends `signal` to every process in the process group `pgid`
+fn signal_group(pgid: i32, signal: libc::c_int) {
+    // SAFETY: kill(2) doesn't touch our memory; a group that's already gone
+    // just fails with ESRCH
+    if unsafe { libc::kill(-pgid, signal) } != 0 {
+        debug!(pgid = %pgid, signal = %signal, "Process group already gone");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Instant};
+
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn rule(command: &str) -> Rule {
+        let yaml = format!("name: test\nmatch: {{on: add}}\ncommand: \"{command}\"\n");
+        Rule::try_from(&YamlLoader::load_from_str(&yaml).unwrap()[0]).unwrap()
+    }
+
+    /// The outcome of each command recorded in the audit log at `path`
+    fn outcomes(path: &std::path::Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|r| r["kind"] == "command")
+            .map(|r| r["outcome"].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn missing_shell_is_audited() {
+        let dir = TempDir::new("exec");
+        let path = dir.join("audit.log");
+        let audit = Audit::open(&path, &Default::default()).unwrap();
+        let config = ExecConfig {
+            shell: Some("/nonexistent/sh".into()),
+            ..Default::default()
+        };
+        let executor = Executor::new(config, Sysfs::new("/sys"), audit);
+
+        executor.spawn(&rule("true"), &(), 0);
+        executor.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(outcomes(&path), ["failed"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_audits_killed_commands() {
+        let dir = TempDir::new("exec");
+        let path = dir.join("audit.log");
+        let audit = Audit::open(&path, &Default::default()).unwrap();
+        let executor = Executor::new(ExecConfig::default(), Sysfs::new("/sys"), audit);
+
+        // Ignored signals stay ignored in `sleep` too, so only SIGKILL works
+        executor.spawn(&rule("trap '' TERM; while :; do sleep 0.1; done"), &(), 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        executor.shutdown(Duration::from_millis(100)).await;
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["outcome"], "finished");
+        assert_eq!(records[0]["signal"], libc::SIGKILL);
+        assert!(executor.running.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_then_terminates() {
+        let dir = TempDir::new("exec");
+        let done = dir.join("done");
+        let killed = dir.join("killed");
+
+        let executor = Executor::new(ExecConfig::default(), Sysfs::new("/sys"), Audit::default());
+        executor.spawn(
+            &rule(&format!("sleep 0.2; touch {}", done.display())),
+            &(),
+            0,
+        );
+        executor.spawn(
+            &rule(&format!(
+                "trap 'touch {}; exit 1' TERM; sleep 30 & wait",
+                killed.display()
+            )),
+            &(),
+            0,
+        );
+        // Let the shells start before they're asked to stop
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let start = Instant::now();
+        executor.shutdown(Duration::from_secs(1)).await;
+        assert!(start.elapsed() < Duration::from_secs(1) + KILL_AFTER);
+        assert!(done.exists());
+        assert!(killed.exists());
+
+        // Nothing new starts once shut down
+        executor.spawn(
+            &rule(&format!("touch {}", dir.join("late").display())),
+            &(),
+            0,
+        );
+        assert!(executor.tasks.lock().is_empty());
+    }
+}
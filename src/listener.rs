@@ -1,41 +1,152 @@
-use std::ffi::OsStr;
+use std::{ffi::OsStr, fmt, io, pin::Pin};
 
-use tokio::sync::{broadcast, mpsc};
-use tokio_stream::StreamExt;
-use tokio_udev::{AsyncMonitorSocket, EventType};
-use tracing::error;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::{Stream, StreamExt};
+use tokio_udev::AsyncMonitorSocket;
+use tracing::{error, span, Level};
 
-use crate::{shutdown::Shutdown, udev::UdevEvent};
+use crate::{rule::Rule, shutdown::Shutdown, udev::UdevEvent, usb::UsbEvent};
+
+/// Which udev events get broadcast
+#[derive(Clone, Debug, PartialEq)]
+pub struct UdevFilter {
+    /// Subsystems to monitor
+    pub subsystems: Vec<String>,
+    /// Only devices of these devtypes (empty allows all)
+    pub devtypes: Vec<String>,
+    /// Only these kinds of events (`All` allows every kind)
+    pub events: Vec<UsbEvent>,
+}
+
+impl Default for UdevFilter {
+    fn default() -> Self {
+        Self {
+            subsystems: vec!["usb".into()],
+            devtypes: vec!["usb_device".into()],
+            events: vec![UsbEvent::All],
+        }
+    }
+}
+
+impl UdevFilter {
+    /// Only the kinds of events that at least one rule is triggered by
+    ///
+    /// Rules `on: all` only match events which are literally `all`, which
+    /// udev never sends, so they don't widen the filter to every kind.
+    pub fn for_rules<'a, I: IntoIterator<Item = &'a Rule>>(rules: I) -> Self {
+        let mut events = Vec::new();
+        let udev_events = rules
+            .into_iter()
+            .filter_map(Rule::on)
+            .filter(|on| !on.is_lifecycle() && *on != UsbEvent::All);
+        for on in udev_events {
+            if !events.contains(&on) {
+                events.push(on);
+            }
+        }
+
+        Self {
+            events,
+            ..Default::default()
+        }
+    }
+
+    /// Also allow the kinds of events in `events`
+    pub fn with_events<I: IntoIterator<Item = UsbEvent>>(mut self, events: I) -> Self {
+        for event in events {
+            if !self.events.contains(&event) {
+                self.events.push(event);
+            }
+        }
+        self
+    }
+
+    pub fn allows_event(&self, event: UsbEvent) -> bool {
+        self.events
+            .iter()
+            .any(|want| *want == UsbEvent::All || *want == event)
+    }
+
+    pub fn allows_devtype(&self, devtype: Option<&OsStr>) -> bool {
+        self.devtypes.is_empty()
+            || devtype.is_some_and(|dt| self.devtypes.iter().any(|want| dt == want.as_str()))
+    }
+
+    /// Whether both the kind of `event` and its devtype are allowed
+    pub fn allows(&self, event: &tokio_udev::Event) -> bool {
+        self.allows_event(event.event_type().into()) && self.allows_devtype(event.devtype())
+    }
+}
+
+/// Listens to udev for devices in `subsystems`, passing on the events
+/// `allows` lets through along with any errors
+pub fn monitor<F>(
+    subsystems: &[String],
+    allows: F,
+) -> io::Result<impl Stream<Item = io::Result<tokio_udev::Event>>>
+where
+    F: Fn(&tokio_udev::Event) -> bool,
+{
+    let mut builder = tokio_udev::MonitorBuilder::new()?;
+    for subsystem in subsystems {
+        builder = builder.match_subsystem(subsystem)?;
+    }
+    Ok(AsyncMonitorSocket::new(builder.listen()?)?
+        .filter(move |e| e.as_ref().map_or(true, &allows)))
+}
+
+/// A udev socket, passing on the events its filter allows
+pub type Monitor = Pin<Box<dyn Stream<Item = io::Result<tokio_udev::Event>>>>;
 
 /// Udev listener state
-#[derive(Debug)]
 pub struct UdevListener {
     /// Broadcasts an event to all active channels.
     pub udev_event_tx: broadcast::Sender<UdevEvent>,
+    /// Which events to pass on; the subsystems are only read when the
+    /// listener starts, while the rest can change as rules are reloaded
+    pub filter: watch::Receiver<UdevFilter>,
     pub shutdown: Shutdown,
     pub shutdown_complete_tx: mpsc::Sender<()>,
+    /// The udev socket, once it's been opened by [`UdevListener::listen`]
+    /// or `run`
+    pub events: Option<Monitor>,
+}
+
+impl fmt::Debug for UdevListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdevListener")
+            .field("filter", &self.filter)
+            .field("listening", &self.events.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 impl UdevListener {
+    /// Opens the udev socket ahead of `run`, so events from now on wait for
+    /// it rather than being missed
+    pub fn listen(&mut self) -> io::Result<()> {
+        if self.events.is_none() {
+            self.events = Some(self.monitor()?);
+        }
+        Ok(())
+    }
+
+    fn monitor(&self) -> io::Result<Monitor> {
+        let subsystems = self.filter.borrow().subsystems.clone();
+        let filter = self.filter.clone();
+        Ok(Box::pin(monitor(&subsystems, move |e| {
+            filter.borrow().allows(e)
+        })?))
+    }
+
     pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut event_iter = AsyncMonitorSocket::new(
-            tokio_udev::MonitorBuilder::new()
-                .unwrap()
-                .match_subsystem("usb")
-                .unwrap()
-                .listen()
-                .unwrap(),
-        )
-        .unwrap()
-        .filter(|e| e.is_ok())
-        .filter(|e| {
-            let et = e.as_ref().unwrap().event_type();
-            et == EventType::Add || et == EventType::Remove
-        })
-        .filter(|e| {
-            let d = e.as_ref().unwrap().device();
-            Some(OsStr::new("usb_interface")) != d.devtype()
-        });
+        let span = span!(Level::TRACE, "fn run", filter = ?self.filter);
+        let _enter = span.enter();
+
+        let mut event_iter = match self.events.take() {
+            Some(events) => events,
+            None => self.monitor()?,
+        };
 
         while !self.shutdown.is_shutdown() {
             let event = tokio::select! {
@@ -58,3 +169,52 @@ impl UdevListener {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rules;
+
+    #[test]
+    fn filter_default() {
+        let f = UdevFilter::default();
+
+        assert!(f.allows_event(UsbEvent::Bind));
+        assert!(f.allows_devtype(Some(OsStr::new("usb_device"))));
+        assert!(!f.allows_devtype(Some(OsStr::new("usb_interface"))));
+        assert!(!f.allows_devtype(None));
+    }
+
+    #[test]
+    fn filter_events() {
+        let f = UdevFilter {
+            events: vec![UsbEvent::Bind],
+            ..Default::default()
+        }
+        .with_events([UsbEvent::Add, UsbEvent::Bind]);
+
+        assert_eq!(f.events, vec![UsbEvent::Bind, UsbEvent::Add]);
+        assert!(f.allows_event(UsbEvent::Add));
+        assert!(!f.allows_event(UsbEvent::Remove));
+    }
+
+    #[test]
+    fn filter_for_rules() {
+        let rules: Rules = "rules:\n  - name: added\n    match: {on: add}\n    command: 'true'\n  - name: all\n    match: {on: all}\n    command: 'true'\n  - name: startup\n    match: {on: startup}\n    command: 'true'\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(UdevFilter::for_rules(&rules.rules).events, [UsbEvent::Add]);
+    }
+
+    #[test]
+    fn filter_any_devtype() {
+        let f = UdevFilter {
+            devtypes: vec![],
+            ..Default::default()
+        };
+
+        assert!(f.allows_devtype(None));
+        assert!(f.allows_devtype(Some(OsStr::new("usb_interface"))));
+    }
+}
@@ -1,16 +1,29 @@
 #[macro_use]
 mod macros;
+mod audit;
+mod authorize;
 mod cli;
+mod config;
+mod control;
 mod ctx;
+mod exec;
 mod listener;
 mod log;
 mod printer;
+mod prompt;
 mod rule;
+mod secure;
 mod shutdown;
+mod source;
 mod state;
+mod systemd;
+mod template;
+#[cfg(test)]
+mod testing;
 mod tokio_udev;
 mod udev;
 mod usb;
+mod watch;
 
 use clap::*;
 
@@ -41,9 +54,9 @@ fn main() {
 
     if let Err(e) = cmd.walk_exec(&mut ctx) {
         if ctx.tracing {
-            tracing::error!("{e}");
+            tracing::error!("{e:#}");
         } else {
-            eprintln!("error: {e}");
+            eprintln!("error: {e:#}");
         }
         std::process::exit(1);
     }
@@ -1,4 +1,6 @@
 use std::{
+    fmt::Debug,
+    io::Write,
     result::Result as StdResult,
     sync::{Mutex, MutexGuard, PoisonError},
 };
@@ -10,7 +12,12 @@ use serde::{
 };
 use strum::{Display, EnumString};
 
-pub use self::_printer::{eprinter, printer, Printer};
+mod table;
+
+pub use self::{
+    _printer::{eprinter, printer, Printer},
+    table::{print_tree, Entry, Table},
+};
 
 static GLOBAL_PRINTER: OnceCell<Mutex<self::_printer::Printer>> = OnceCell::new();
 static GLOBAL_EPRINTER: OnceCell<Mutex<self::_printer::Printer>> = OnceCell::new();
@@ -22,6 +29,50 @@ pub enum OutFormat {
     Raw,
     #[default]
     Yaml,
+    Json,
+    Ndjson,
+    /// Human readable columns (`scan` and `listen` only)
+    Table,
+    /// Devices nested under their hubs (`scan` only)
+    Tree,
+}
+
+impl OutFormat {
+    /// The format to use when appending documents to a file rather than
+    /// displaying them
+    pub fn for_file(self) -> Self {
+        match self {
+            OutFormat::Raw | OutFormat::Table | OutFormat::Tree => OutFormat::Yaml,
+            OutFormat::Json | OutFormat::Ndjson => OutFormat::Ndjson,
+            f => f,
+        }
+    }
+
+    /// Renders `value` as a single document, including the trailing newline
+    ///
+    /// The `table` and `tree` formats only apply to ports and devices and are
+    /// displayed as YAML here.
+    pub fn render<T: Serialize + Debug>(self, value: &T) -> anyhow::Result<String> {
+        Ok(match self {
+            OutFormat::Table | OutFormat::Tree => return OutFormat::Yaml.render(value),
+            OutFormat::Raw => format!("{value:#?}\n"),
+            OutFormat::Yaml => format!("---\n{}", serde_yaml::to_string(value)?),
+            OutFormat::Json => format!("{}\n", serde_json::to_string_pretty(value)?),
+            OutFormat::Ndjson => format!("{}\n", serde_json::to_string(value)?),
+        })
+    }
+}
+
+/// Prints `value` to stdout as a single document in `format`
+pub fn print_doc<T: Serialize + Debug>(value: &T, format: OutFormat) -> anyhow::Result<()> {
+    let doc = format.render(value)?;
+    if crate::log::log_level() <= &crate::log::LogLevel::Info {
+        let mut ptr = printer();
+        ptr.write_all(doc.as_bytes())?;
+        // Consumers such as `jq` want each document as soon as it's ready
+        ptr.flush()?;
+    }
+    Ok(())
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, clap::ValueEnum, Display, EnumString)]
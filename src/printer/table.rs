@@ -0,0 +1,319 @@
+use std::io::Write;
+
+use super::{printer, Color};
+use crate::{
+    cli::ForObject,
+    log::{log_level, LogLevel},
+    usb::{UsbDevice, UsbEvent, UsbPort},
+};
+
+/// Displayed in place of values udev didn't provide
+const MISSING: &str = "-";
+
+/// A port, the device plugged into it, and what just happened to it (if
+/// anything)
+pub struct Entry<'a> {
+    pub port: &'a UsbPort,
+    pub device: &'a UsbDevice,
+    pub event: Option<UsbEvent>,
+}
+
+impl Entry<'_> {
+    fn id(&self) -> String {
+        match (self.device.vendor_id(), self.device.model_id()) {
+            (None, None) => MISSING.into(),
+            (v, m) => format!("{}:{}", v.unwrap_or("????"), m.unwrap_or("????")),
+        }
+    }
+
+    fn name(&self) -> String {
+        match (self.device.vendor(), self.device.model()) {
+            (Some(v), Some(m)) => format!("{v} {m}"),
+            (Some(s), None) | (None, Some(s)) => s.into(),
+            (None, None) => MISSING.into(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Column {
+    Port,
+    DevPath,
+    IdPath,
+    Id,
+    Vendor,
+    Model,
+    Serial,
+    Event,
+}
+
+impl Column {
+    fn header(self) -> &'static str {
+        match self {
+            Column::Port => "PORT",
+            Column::DevPath => "DEVPATH",
+            Column::IdPath => "ID_PATH",
+            Column::Id => "ID",
+            Column::Vendor => "VENDOR",
+            Column::Model => "MODEL",
+            Column::Serial => "SERIAL",
+            Column::Event => "EVENT",
+        }
+    }
+
+    /// Used when rows are streamed and can't be measured up front
+    fn default_width(self) -> usize {
+        match self {
+            Column::Port => 10,
+            Column::DevPath => 48,
+            Column::IdPath => 28,
+            Column::Id => 9,
+            Column::Vendor | Column::Model => 24,
+            Column::Serial => 20,
+            Column::Event => 6,
+        }
+    }
+
+    fn value(self, entry: &Entry) -> String {
+        let s = match self {
+            Column::Port => entry.port.sysname(),
+            Column::DevPath => entry.port.devpath(),
+            Column::IdPath => entry.port.id_path(),
+            Column::Id => return entry.id(),
+            Column::Vendor => entry.device.vendor(),
+            Column::Model => entry.device.model(),
+            Column::Serial => entry.device.serial(),
+            Column::Event => return entry.event.map_or(MISSING.into(), |e| e.to_string()),
+        };
+        s.unwrap_or(MISSING).into()
+    }
+
+    fn color(self, entry: &Entry) -> Option<Color> {
+        match self {
+            Column::Event => entry.event.and_then(event_color),
+            _ => None,
+        }
+    }
+}
+
+/// The color used to highlight a kind of event
+fn event_color(event: UsbEvent) -> Option<Color> {
+    match event {
+        UsbEvent::Add => Some(Color::Green),
+        UsbEvent::Remove => Some(Color::Red),
+        UsbEvent::Bind | UsbEvent::Unbind => Some(Color::Cyan),
+        UsbEvent::Change => Some(Color::Yellow),
+        UsbEvent::Unknown
+        | UsbEvent::All
+        | UsbEvent::Startup
+        | UsbEvent::Shutdown
+        | UsbEvent::Reload => None,
+    }
+}
+
+fn write(text: &str, color: Option<Color>) {
+    if log_level() > &LogLevel::Info {
+        return;
+    }
+    let mut ptr = printer();
+    if let Some(color) = color {
+        ptr.set_color(color);
+    }
+    let _ = ptr.write_all(text.as_bytes());
+    ptr.reset();
+}
+
+/// One line per port and/or device
+pub struct Table {
+    columns: Vec<Column>,
+}
+
+impl Table {
+    /// A table showing the KIND of objects asked for, and optionally which
+    /// event occurred
+    pub fn new(only: ForObject, events: bool) -> Self {
+        let mut columns = Vec::new();
+        if only.ports() {
+            columns.push(Column::Port);
+        }
+        if only == ForObject::Ports {
+            columns.extend([Column::DevPath, Column::IdPath]);
+        }
+        if only.devices() {
+            columns.extend([Column::Id, Column::Vendor, Column::Model, Column::Serial]);
+        }
+        if events {
+            columns.push(Column::Event);
+        }
+
+        Self { columns }
+    }
+
+    /// Prints the whole table with each column sized to fit
+    pub fn print(&self, entries: &[Entry]) {
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .map(|c| {
+                entries
+                    .iter()
+                    .map(|e| c.value(e).chars().count())
+                    .chain([c.header().len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        self.print_header_with(&widths);
+        for entry in entries {
+            self.print_row_with(entry, &widths);
+        }
+    }
+
+    /// Prints the header of a table whose rows will arrive one at a time
+    pub fn print_header(&self) { self.print_header_with(&self.default_widths()) }
+
+    /// Prints a single row of a table started with [`Table::print_header`]
+    pub fn print_row(&self, entry: &Entry) { self.print_row_with(entry, &self.default_widths()) }
+
+    fn default_widths(&self) -> Vec<usize> {
+        self.columns.iter().map(|c| c.default_width()).collect()
+    }
+
+    fn print_header_with(&self, widths: &[usize]) {
+        let cells = self.columns.iter().map(|c| (c.header().to_string(), None));
+        print_cells(cells, widths);
+    }
+
+    fn print_row_with(&self, entry: &Entry, widths: &[usize]) {
+        let cells = self
+            .columns
+            .iter()
+            .map(|c| (c.value(entry), c.color(entry)));
+        print_cells(cells, widths);
+    }
+}
+
+fn print_cells<I>(cells: I, widths: &[usize])
+where
+    I: Iterator<Item = (String, Option<Color>)>,
+{
+    let last = widths.len().saturating_sub(1);
+    for (i, ((text, color), width)) in cells.zip(widths).enumerate() {
+        write(&text, color);
+        if i != last {
+            let pad = width.saturating_sub(text.chars().count()) + 2;
+            write(&" ".repeat(pad), None);
+        }
+    }
+    write("\n", None);
+}
+
+/// Prints entries nested under the hubs they're plugged into
+///
+/// Nesting is determined by `devpath`, i.e. `.../usb2/2-1/2-1.4` is displayed
+/// under `.../usb2/2-1`.
+pub fn print_tree(entries: &[Entry]) {
+    let parents: Vec<Option<usize>> = entries.iter().map(|e| parent_of(e, entries)).collect();
+
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i].port.devpath());
+
+    let roots: Vec<usize> = order
+        .iter()
+        .copied()
+        .filter(|&i| parents[i].is_none())
+        .collect();
+    let children = |p: usize| -> Vec<usize> {
+        order
+            .iter()
+            .copied()
+            .filter(|&i| parents[i] == Some(p))
+            .collect()
+    };
+
+    fn walk(
+        i: usize,
+        prefix: &str,
+        last: bool,
+        root: bool,
+        entries: &[Entry],
+        children: &dyn Fn(usize) -> Vec<usize>,
+    ) {
+        let entry = &entries[i];
+        if !root {
+            write(prefix, None);
+            write(if last { "└── " } else { "├── " }, None);
+        }
+        write(entry.port.sysname().unwrap_or(MISSING), Some(Color::Cyan));
+        write(&format!("  {}  {}", entry.id(), entry.name()), None);
+        if let Some(serial) = entry.device.serial() {
+            write(&format!("  ({serial})"), None);
+        }
+        write("\n", None);
+
+        let kids = children(i);
+        let prefix = match (root, last) {
+            (true, _) => String::new(),
+            (false, true) => format!("{prefix}    "),
+            (false, false) => format!("{prefix}│   "),
+        };
+        for (n, k) in kids.iter().enumerate() {
+            walk(*k, &prefix, n + 1 == kids.len(), false, entries, children);
+        }
+    }
+
+    for r in roots {
+        walk(r, "", true, true, entries, &children);
+    }
+}
+
+/// The entry with the longest `devpath` that is an ancestor of `entry`
+fn parent_of(entry: &Entry, entries: &[Entry]) -> Option<usize> {
+    let path = entry.port.devpath()?;
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| {
+            let p = e.port.devpath()?;
+            let is_ancestor =
+                path.len() > p.len() && path.starts_with(p) && path.as_bytes()[p.len()] == b'/';
+            is_ancestor.then_some((i, p.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(devpath: &str) -> UsbPort {
+        serde_yaml::from_str(&format!("devpath: {devpath}")).unwrap()
+    }
+
+    #[test]
+    fn tree_parent_is_closest_ancestor() {
+        let ports = [
+            port("/devices/pci0000:00/0000:00:14.0/usb2"),
+            port("/devices/pci0000:00/0000:00:14.0/usb2/2-1"),
+            port("/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1.4"),
+            port("/devices/pci0000:00/0000:00:14.0/usb2/2-10"),
+        ];
+        let device = UsbDevice::default();
+        let entries: Vec<_> = ports
+            .iter()
+            .map(|port| Entry {
+                port,
+                device: &device,
+                event: None,
+            })
+            .collect();
+
+        assert_eq!(parent_of(&entries[0], &entries), None);
+        assert_eq!(parent_of(&entries[1], &entries), Some(0));
+        assert_eq!(parent_of(&entries[2], &entries), Some(1));
+        // 2-10 is not behind 2-1 even though it shares a prefix
+        assert_eq!(parent_of(&entries[3], &entries), Some(0));
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{self, BufRead, Write};
+
+/// Asks the user questions on the terminal
+///
+/// Generic over where answers come from and questions go, so interactive
+/// commands can be driven by canned answers.
+pub struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl Prompt<io::StdinLock<'static>, io::Stdout> {
+    pub fn stdio() -> Self { Self::new(io::stdin().lock(), io::stdout()) }
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    pub fn new(input: R, output: W) -> Self { Self { input, output } }
+
+    /// Displays a line of information
+    pub fn say(&mut self, msg: &str) -> io::Result<()> {
+        writeln!(self.output, "{msg}")?;
+        self.output.flush()
+    }
+
+    /// The trimmed answer to `question`, or `None` at end of input
+    pub fn ask(&mut self, question: &str) -> io::Result<Option<String>> {
+        write!(self.output, "{question}")?;
+        self.output.flush()?;
+
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(answer.trim().into()))
+    }
+
+    /// A yes or no answer, where an empty answer is `default` and end of input
+    /// is no
+    pub fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "[Y/n]" } else { "[y/N]" };
+        loop {
+            let Some(answer) = self.ask(&format!("{question} {hint} "))? else {
+                return Ok(false);
+            };
+            match answer.to_ascii_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.say("Please answer 'y' or 'n'")?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_answers() {
+        let mut out = Vec::new();
+        let mut p = Prompt::new(&b" Left \nwhat\ny\n\n"[..], &mut out);
+
+        assert_eq!(p.ask("Name? ").unwrap().as_deref(), Some("Left"));
+        assert!(p.confirm("Again?", false).unwrap());
+        assert!(p.confirm("Again?", true).unwrap());
+        assert!(!p.confirm("Again?", true).unwrap());
+        assert_eq!(p.ask("Name? ").unwrap(), None);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Please answer 'y' or 'n'"));
+    }
+}
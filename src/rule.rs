@@ -1,86 +1,432 @@
 mod r#match;
+mod state;
 
-use std::{fmt::Debug, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use serde::Serialize;
-use tracing::{debug, span, Level};
-use yaml_rust::Yaml;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, span, warn, Level};
+use yaml_rust::{Yaml, YamlLoader};
 
-use crate::udev::UdevEvent;
+use crate::{
+    authorize::Authorize,
+    template::Template,
+    udev::UdevEvent,
+    usb::{UsbDevice, UsbEvent, UsbPort},
+};
 
-use r#match::Match;
+pub use r#match::Match;
+pub use state::{Attached, StateChange, StateMatch, Tracker};
+
+/// What the command of a rule triggered by the daemon itself (i.e.
+/// `on: startup`) is run with
+///
+/// Rules which match devices or ports run once for each attached device they
+/// match, with that device and its port, like an `add` rule would. Other rules
+/// run once, with every attached device.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Lifecycle {
+    pub event: UsbEvent,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub attached: Option<Attached>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<Attached>>,
+}
 
 #[derive(Serialize, Debug, PartialEq)]
 pub struct Rules {
     pub rules: Vec<Rule>,
 }
 
-impl<'a> From<&'a Yaml> for Rules {
-    fn from(yaml: &'a Yaml) -> Self {
+impl Rules {
+    /// Parses a rules file, failing on the first invalid rule rather than
+    /// skipping it
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let buf = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::from_str(&buf).with_context(|| format!("invalid rules in {}", path.display()))
+    }
+
+    /// Parses each of the [`rule_files`] in `paths` in order, requiring rule
+    /// names to be unique across all of them
+    pub fn from_paths(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut rules = Vec::new();
+        let mut sources: HashMap<String, PathBuf> = HashMap::new();
+        for file in rule_files(paths)? {
+            for rule in Self::from_path(&file)?.rules {
+                if let Some(first) = sources.get(&rule.name) {
+                    bail!(
+                        "rule '{}' in {} is already defined in {}",
+                        rule.name,
+                        file.display(),
+                        first.display()
+                    );
+                }
+                sources.insert(rule.name.clone(), file.clone());
+                rules.push(rule);
+            }
+        }
+
+        Ok(Self { rules })
+    }
+}
+
+/// The rules files given by `paths`, where a directory stands for the `*.yml`
+/// files directly inside it in lexical order
+pub fn rule_files(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+
+        let mut in_dir = Vec::new();
+        for entry in
+            fs::read_dir(path).with_context(|| format!("failed to read {}", path.display()))?
+        {
+            let file = entry?.path();
+            if is_rules_file(&file) && !file.is_dir() {
+                in_dir.push(file);
+            }
+        }
+        in_dir.sort();
+        files.append(&mut in_dir);
+    }
+
+    Ok(files)
+}
+
+/// Whether `path` is one a rules directory would load
+pub fn is_rules_file(path: &Path) -> bool { path.extension().is_some_and(|ext| ext == "yml") }
+
+impl FromStr for Rules {
+    type Err = anyhow::Error;
+
+    fn from_str(buf: &str) -> anyhow::Result<Self> {
+        let docs = YamlLoader::load_from_str(buf)?;
+        match docs.first() {
+            Some(yaml) => Self::try_from(yaml),
+            None => Ok(Self { rules: Vec::new() }),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Yaml> for Rules {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
         let mut rules = Vec::new();
         if let Some(yaml_rules) = yaml["rules"].as_vec() {
             for r in yaml_rules {
-                rules.push(Rule::from(r));
+                rules.push(Rule::try_from(r)?);
             }
         }
 
-        Self { rules }
+        Ok(Self { rules })
     }
 }
 
-#[derive(Serialize, PartialEq, Debug)]
+#[derive(Serialize, PartialEq, Debug, Clone)]
 pub struct Rule {
     pub name: String,
-    r#match: Match,
-    pub command_shell: PathBuf,
-    pub command: String,
+    /// Matches single events; rules have either this or `state`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#match: Option<Match>,
+    /// Matches what's attached at the moment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<StateMatch>,
+    /// Shell to run the command with instead of the configured one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_shell: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// `command`, parsed once when the rule is loaded
+    #[serde(skip)]
+    template: Option<Template>,
+    /// Authorizes or deauthorizes the matching device, before any command
+    /// runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorize: Option<Authorize>,
 }
 
 impl Rule {
+    /// The kind of event that triggers this rule, if it's triggered by events
+    pub fn on(&self) -> Option<UsbEvent> { self.r#match.as_ref().map(Match::on) }
+
+    pub fn state(&self) -> Option<&StateMatch> { self.state.as_ref() }
+
+    pub fn state_mut(&mut self) -> Option<&mut StateMatch> { self.state.as_mut() }
+
+    /// Whether the rule is triggered by `event` from the daemon itself once
+    /// for each matching device, rather than once
+    pub fn per_device(&self) -> bool { self.r#match.as_ref().is_some_and(Match::selects_devices) }
+
+    /// Whether a device attached to a port matches, ignoring the event
+    pub fn matches_attached(&self, port: &UsbPort, device: &UsbDevice) -> bool {
+        self.r#match.as_ref().is_some_and(|m| {
+            m.matches_port(port) && m.matches_device(device) && m.matches_interfaces(device)
+        })
+    }
+
+    /// Files included by the rule's devices and ports
+    pub fn includes(&self) -> Vec<&Path> {
+        let from_match = self.r#match.iter().flat_map(|m| m.includes());
+        let from_state = self.state.iter().flat_map(|s| s.includes());
+        from_match.chain(from_state).map(PathBuf::as_path).collect()
+    }
+
     pub fn matches_udev_event(&self, event: &UdevEvent) -> bool {
         let span = span!(Level::TRACE, "fn matches_udev_event", rule = %self.name);
         let _enter = span.enter();
 
-        self.r#match.matches_udev_event(event)
+        self.r#match
+            .as_ref()
+            .is_some_and(|m| m.matches_udev_event(event))
+    }
+
+    /// Matches everything but child nodes, see [`Rule::needs_nodes`]
+    pub fn matches_device_event(&self, event: &UdevEvent) -> bool {
+        let span = span!(Level::TRACE, "fn matches_device_event", rule = %self.name);
+        let _enter = span.enter();
+
+        self.r#match
+            .as_ref()
+            .is_some_and(|m| m.matches_device_event(event))
+    }
+
+    /// Whether the rule matches on child nodes (i.e. `block:`) which appear
+    /// some time after the USB device is added
+    pub fn needs_nodes(&self) -> bool { self.r#match.as_ref().is_some_and(Match::needs_nodes) }
+
+    pub fn node_subsystems(&self) -> Vec<&str> {
+        self.r#match
+            .as_ref()
+            .map(Match::node_subsystems)
+            .unwrap_or_default()
+    }
+
+    /// How long to wait for child nodes to appear
+    pub fn wait(&self) -> Duration { self.r#match.as_ref().map_or(Duration::ZERO, Match::wait) }
+
+    /// The command with any `{{placeholders}}` filled in from `event`, either
+    /// a [`UdevEvent`] or a [`StateChange`], if the rule has one
+    ///
+    /// Values are shell quoted, since devices control many of them (i.e.
+    /// `{{nodes.block.0.ID_FS_LABEL}}`). Nothing is run when the command
+    /// can't be filled in.
+    pub fn command_for<T: Serialize>(&self, event: &T) -> Option<String> {
+        let template = self.template.as_ref()?;
+        match template.render_shell(event) {
+            Ok(command) => Some(command),
+            Err(err) => {
+                warn!(rule = %self.name, cause = %err, "Failed to fill in command; not running it");
+                None
+            }
+        }
     }
 }
 
-impl<'a> From<&'a Yaml> for Rule {
-    fn from(yaml: &'a Yaml) -> Self {
-        let span = span!(Level::TRACE, "fn From::<Yaml>");
+impl<'a> TryFrom<&'a Yaml> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let span = span!(Level::TRACE, "fn TryFrom::<Yaml>");
         let _enter = span.enter();
 
         let name: String = if let Some(name) = yaml["name"].as_str() {
             debug!(name = %name, "Building Rule");
             name.into()
         } else {
-            todo!("Handle Rule::from<Yaml> with no name key")
+            bail!("rule is missing required 'name' key");
         };
+        Self::parse(name.clone(), yaml).with_context(|| format!("rule '{name}'"))
+    }
+}
 
-        let yaml_match = &yaml["match"];
-        let m = if !yaml_match.is_badvalue() {
-            Match::from(yaml_match)
-        } else {
-            cli_bail!("failed to parse YAML for Rule; missing required 'match' key");
+impl Rule {
+    fn parse(name: String, yaml: &Yaml) -> anyhow::Result<Self> {
+        let (m, state) = match (&yaml["match"], &yaml["state"]) {
+            (Yaml::BadValue, Yaml::BadValue) => bail!("missing required 'match' or 'state' key"),
+            (yaml_match, Yaml::BadValue) => (Some(Match::try_from(yaml_match)?), None),
+            (Yaml::BadValue, yaml_state) => (None, Some(StateMatch::try_from(yaml_state)?)),
+            _ => bail!("'match' and 'state' can't both be used"),
         };
 
-        let command_shell = if let Some(s) = yaml["command_shell"].as_str() {
-            PathBuf::from(s)
-        } else {
-            PathBuf::from("/bin/sh")
-        };
+        let command_shell = yaml["command_shell"].as_str().map(PathBuf::from);
 
-        let command: String = if let Some(c) = yaml["command"].as_str() {
-            c.into()
-        } else {
-            cli_bail!("failed to parse YAML for Rule; missing required 'command' key");
+        let command = yaml["command"].as_str().map(String::from);
+        let template = command
+            .as_deref()
+            .map(|c| {
+                let template = c.parse::<Template>()?;
+                template.check_shell()?;
+                anyhow::Ok(template)
+            })
+            .transpose()
+            .context("invalid 'command' template")?;
+
+        let authorize = match &yaml["authorize"] {
+            Yaml::BadValue => None,
+            value => match value.as_str().map(str::parse) {
+                Some(Ok(authorize)) => Some(authorize),
+                _ => bail!("'authorize' must be one of 'allow' or 'deny'"),
+            },
         };
+        if authorize.is_some() {
+            match m.as_ref() {
+                None => bail!("'authorize' can only be used with 'match'"),
+                // The device is gone by the time a remove is seen
+                Some(m) if matches!(m.on(), UsbEvent::Remove | UsbEvent::All) => {
+                    bail!("'authorize' can't be used with 'on: {}'", m.on())
+                }
+                // Otherwise it would apply to every attached device at
+                // startup and on every reload, hubs (and so the keyboard and
+                // mouse behind them) included
+                Some(m) if !m.selects_devices() => bail!(
+                    "'authorize' with 'on: {}' requires devices, ports or interfaces to select",
+                    m.on()
+                ),
+                _ => (),
+            }
+        }
+        if command.is_none() && authorize.is_none() {
+            bail!("missing required 'command' or 'authorize' key");
+        }
 
-        Rule {
+        Ok(Rule {
             name,
             r#match: m,
+            state,
             command_shell,
             command,
+            template,
+            authorize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+    use crate::{testing::TempDir, usb::DeviceNode};
+
+    #[test]
+    fn command_template() {
+        let yaml = YamlLoader::load_from_str(
+            "name: backup\nmatch:\n  on: add\n  block: {}\ncommand: mount {{nodes.block.0.DEVNAME}} /mnt\n",
+        )
+        .unwrap();
+        let rule = Rule::try_from(&yaml[0]).unwrap();
+        let event = UdevEvent {
+            event_kind: UsbEvent::Add,
+            device: UsbDevice::new("foo"),
+            port: UsbPort::new("bar"),
+            nodes: [(
+                "block".into(),
+                vec![DeviceNode {
+                    sysname: "sdb1".into(),
+                    properties: [("DEVNAME".into(), "/dev/sdb1".into())].into(),
+                }],
+            )]
+            .into(),
+        };
+
+        assert_eq!(rule.command_for(&event).unwrap(), "mount '/dev/sdb1' /mnt");
+    }
+
+    #[test]
+    fn command_placeholder_in_quotes() {
+        let yaml = YamlLoader::load_from_str(
+            "name: log\nmatch:\n  on: add\ncommand: echo \"at {{port.sysname}}\" >> usb.log\n",
+        )
+        .unwrap();
+        let err = Rule::try_from(&yaml[0]).unwrap_err();
+
+        assert_eq!(
+            format!("{err:#}"),
+            "rule 'log': invalid 'command' template: '{{port.sysname}}' is inside quotes; \
+             use it as a whole word, it's quoted already"
+        );
+    }
+
+    #[test]
+    fn rules_invalid() {
+        let err = "rules:\n  - name: ok\n    match: {on: add}\n    command: 'true'\n  - name: broken\n    match: {on: sometimes}\n    command: 'true'\n"
+            .parse::<Rules>()
+            .unwrap_err();
+        assert!(format!("{err:#}").starts_with("rule 'broken': match 'on' must be"));
+
+        assert!("rules:\n  - match: {on: add}\n    command: 'true'\n"
+            .parse::<Rules>()
+            .is_err());
+        assert!("rules: [".parse::<Rules>().is_err());
+        assert!("".parse::<Rules>().unwrap().rules.is_empty());
+    }
+
+    #[test]
+    fn rule_authorize() {
+        let rules: Rules = "rules:\n  - name: no-sandisk\n    match:\n      on: add\n      devices: [{name: stick, ID_VENDOR_ID: '0781'}]\n    authorize: deny\n"
+            .parse()
+            .unwrap();
+        assert_eq!(rules.rules[0].authorize, Some(Authorize::Deny));
+        assert_eq!(rules.rules[0].command_for(&()), None);
+
+        let startup: Rules = "rules:\n  - name: deny-sandisk\n    match:\n      on: startup\n      devices: [{name: stick, ID_VENDOR_ID: '0781'}]\n    authorize: deny\n"
+            .parse()
+            .unwrap();
+        assert!(startup.rules[0].per_device());
+
+        for invalid in [
+            "match: {on: add}\n    authorize: maybe",
+            "match: {on: remove}\n    authorize: allow",
+            "match: {on: all}\n    authorize: deny",
+            "match: {on: startup}\n    authorize: deny",
+            "match: {on: reload}\n    authorize: allow",
+            "match: {on: add}\n    authorize: deny",
+            "state: {attached: {}}\n    authorize: allow",
+            "match: {on: add}",
+        ] {
+            let buf = format!("rules:\n  - name: bad\n    {invalid}\n");
+            assert!(buf.parse::<Rules>().is_err(), "{invalid}");
         }
     }
+
+    #[test]
+    fn rules_from_paths() {
+        let dir = TempDir::new("rules");
+        let rules_d = dir.join("rules.d");
+        fs::create_dir_all(&rules_d).unwrap();
+        let rule = |name: &str| {
+            format!("rules:\n  - name: {name}\n    match: {{on: add}}\n    command: 'true'\n")
+        };
+        fs::write(dir.join("main.yml"), rule("main")).unwrap();
+        fs::write(rules_d.join("20-second.yml"), rule("second")).unwrap();
+        fs::write(rules_d.join("10-first.yml"), rule("first")).unwrap();
+        fs::write(rules_d.join("README"), "not rules").unwrap();
+
+        let paths = [dir.join("main.yml"), rules_d.clone()];
+        let names: Vec<_> = Rules::from_paths(&paths)
+            .unwrap()
+            .rules
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, ["main", "first", "second"]);
+
+        fs::write(rules_d.join("30-again.yml"), rule("main")).unwrap();
+        let err = Rules::from_paths(&paths).unwrap_err().to_string();
+        assert!(err.contains("30-again.yml is already defined in"));
+        assert!(err.ends_with("main.yml"));
+    }
 }
@@ -1,32 +1,152 @@
-use std::{fmt::Debug, fs::File};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::{bail, Context};
 use serde::Serialize;
 use tracing::{debug, span, trace, Level};
 use yaml_rust::Yaml;
 
 use crate::{
     udev::UdevEvent,
-    usb::{UsbDevice, UsbDevices, UsbEvent, UsbPort, UsbPorts},
+    usb::{
+        yaml_code, yaml_map, DeviceNodes, UsbDevice, UsbEvent, UsbInterface, UsbInventory, UsbPort,
+        NODE_SUBSYSTEMS,
+    },
 };
 
-#[derive(Serialize, PartialEq, Debug)]
+/// How long to wait for the child nodes of a newly added device when a rule
+/// doesn't say
+const DEFAULT_NODE_WAIT: Duration = Duration::from_secs(10);
+
+/// Matches devices exposing at least one interface with all of the given
+/// codes, i.e. `contains_class: 03` for any HID device
+#[derive(Serialize, PartialEq, Debug, Default, Clone)]
+pub struct InterfaceMatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contains_class: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contains_subclass: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contains_protocol: Option<u8>,
+}
+
+impl InterfaceMatch {
+    pub fn matches(&self, iface: &UsbInterface) -> bool {
+        self.contains_class.map_or(true, |c| c == iface.class)
+            && self.contains_subclass.map_or(true, |c| c == iface.subclass)
+            && self.contains_protocol.map_or(true, |c| c == iface.protocol)
+    }
+
+    pub fn matches_device(&self, device: &UsbDevice) -> bool {
+        device.interfaces().iter().any(|i| self.matches(i))
+    }
+}
+
+impl<'a> TryFrom<&'a Yaml> for InterfaceMatch {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let code = |key: &str| {
+            let val = &yaml[key];
+            if val.is_badvalue() {
+                return Ok(None);
+            }
+            match yaml_code(val) {
+                Some(c) => Ok(Some(c)),
+                None => bail!("interfaces '{key}' must be a hex code such as 03"),
+            }
+        };
+
+        let m = Self {
+            contains_class: code("contains_class")?,
+            contains_subclass: code("contains_subclass")?,
+            contains_protocol: code("contains_protocol")?,
+        };
+        if m == Self::default() {
+            bail!("interfaces requires at least one of 'contains_class', 'contains_subclass' or 'contains_protocol'");
+        }
+
+        Ok(m)
+    }
+}
+
+/// Matches devices with a child node in `subsystem` that has all the given
+/// properties, i.e. `block: {ID_FS_LABEL: BACKUP}`
+///
+/// A list of property sets matches if any one of them does.
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct NodeMatch {
+    subsystem: String,
+    any_of: Vec<BTreeMap<String, String>>,
+}
+
+impl NodeMatch {
+    pub fn matches(&self, nodes: &DeviceNodes) -> bool {
+        let Some(nodes) = nodes.get(&self.subsystem) else {
+            return false;
+        };
+        nodes.iter().any(|n| {
+            self.any_of
+                .iter()
+                .any(|props| props.iter().all(|(k, v)| n.property(k) == Some(v)))
+        })
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
 pub struct Match {
     on: UsbEvent,
     devices: Vec<UsbDevice>,
     ports: Vec<UsbPort>,
     ignore_devices: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    interfaces: Vec<InterfaceMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    nodes: Vec<NodeMatch>,
+    /// Seconds to wait for matching child nodes to appear
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wait: Option<u64>,
+    /// Files loaded by `include_devices`, `exclude_devices` and
+    /// `include_ports`
+    #[serde(skip)]
+    includes: Vec<PathBuf>,
 }
 
 impl Match {
-    fn new(event: UsbEvent) -> Self {
+    pub fn new(event: UsbEvent) -> Self {
         Self {
             on: event,
             devices: Vec::new(),
             ports: Vec::new(),
             ignore_devices: Vec::new(),
+            interfaces: Vec::new(),
+            nodes: Vec::new(),
+            wait: None,
+            includes: Vec::new(),
         }
     }
 
+    pub fn on(&self) -> UsbEvent { self.on }
+
+    /// Files the devices and ports were loaded from
+    pub fn includes(&self) -> &[PathBuf] { &self.includes }
+
+    /// Matches any of `devices` (in addition to those already matched)
+    pub fn with_devices(mut self, mut devices: Vec<UsbDevice>) -> Self {
+        self.devices.append(&mut devices);
+        self
+    }
+
+    /// Matches any of `ports` (in addition to those already matched)
+    pub fn with_ports(mut self, mut ports: Vec<UsbPort>) -> Self {
+        self.ports.append(&mut ports);
+        self
+    }
+
     pub fn device_ignored(&self, device: &UsbDevice) -> bool {
         let span = span!(Level::TRACE, "fn device_ignored", %device);
         let _enter = span.enter();
@@ -76,6 +196,17 @@ impl Match {
         ret
     }
 
+    /// Any device matches when no interface matchers were given
+    pub fn matches_interfaces(&self, device: &UsbDevice) -> bool {
+        let span = span!(Level::TRACE, "fn matches_interfaces", device = %device);
+        let _enter = span.enter();
+
+        let ret =
+            self.interfaces.is_empty() || self.interfaces.iter().any(|m| m.matches_device(device));
+        trace!(interfaces = ?device.interfaces(), returning = ?ret);
+        ret
+    }
+
     pub fn matches_usb_event(&self, event: &UsbEvent) -> bool {
         let span = span!(Level::TRACE, "fn matches_usb_event", ?event);
         let _enter = span.enter();
@@ -84,48 +215,114 @@ impl Match {
         &self.on == event
     }
 
-    pub fn matches_udev_event(&self, event: &UdevEvent) -> bool {
-        self.matches_usb_event(&event.event_kind)
-            && self.matches_port(&event.port)
+    /// Whether any devices, ports or interfaces were given, rather than
+    /// matching everything
+    pub fn selects_devices(&self) -> bool {
+        !self.devices.is_empty()
+            || !self.ignore_devices.is_empty()
+            || !self.ports.is_empty()
+            || !self.interfaces.is_empty()
+    }
+
+    /// Whether the rule matches on child nodes, and so has to wait for them
+    /// to appear after a device is added
+    pub fn needs_nodes(&self) -> bool { !self.nodes.is_empty() }
+
+    /// The subsystems of the child nodes matched on
+    pub fn node_subsystems(&self) -> Vec<&str> {
+        let mut subsystems: Vec<&str> = self.nodes.iter().map(|n| n.subsystem.as_str()).collect();
+        subsystems.dedup();
+        subsystems
+    }
+
+    /// How long to wait for child nodes to appear
+    pub fn wait(&self) -> Duration { self.wait.map_or(DEFAULT_NODE_WAIT, Duration::from_secs) }
+
+    /// All node matchers must match (any node matches when there are none)
+    pub fn matches_nodes(&self, nodes: &DeviceNodes) -> bool {
+        let ret = self.nodes.iter().all(|m| m.matches(nodes));
+        trace!(?nodes, returning = ?ret, "fn matches_nodes");
+        ret
+    }
+
+    /// Matches everything but child nodes
+    pub fn matches_device_event(&self, event: &UdevEvent) -> bool {
+        self.matches_usb_event(&event.event_kind) && self.matches_selected(event)
+    }
+
+    /// Matches the port, device and interfaces, whatever kind of event it is
+    pub fn matches_selected(&self, event: &UdevEvent) -> bool {
+        self.matches_port(&event.port)
             && self.matches_device(&event.device)
+            && self.matches_interfaces(&event.device)
+    }
+
+    pub fn matches_udev_event(&self, event: &UdevEvent) -> bool {
+        self.matches_device_event(event) && self.matches_nodes(&event.nodes)
+    }
+
+    /// Swaps devices and ports given only by name for the loaded definitions
+    /// with the same name
+    pub fn resolve_names(&mut self, devices: &[UsbDevice], ports: &[UsbPort]) {
+        for device in self.devices.iter_mut().filter(|d| d.is_empty()) {
+            if let Some(known) = devices.iter().find(|k| k.name == device.name) {
+                debug!(device = %device, "Resolved device by name");
+                *device = known.clone();
+            }
+        }
+        resolve_ports(&mut self.ports, ports);
     }
 }
 
-impl<'a> From<&'a Yaml> for Match {
-    fn from(yaml: &'a Yaml) -> Self {
-        let span = span!(Level::TRACE, "fn From::<Yaml>");
+impl<'a> TryFrom<&'a Yaml> for Match {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let span = span!(Level::TRACE, "fn TryFrom::<Yaml>");
         let _enter = span.enter();
 
-        let mut m = if let Some(on_event) = yaml["on"].as_str() {
-            Match::new(on_event.parse().unwrap())
-        } else {
-            cli_bail!("failed to parse YAML for Rule; missing required 'on' key");
+        let Some(on_event) = yaml["on"].as_str() else {
+            bail!("match is missing required 'on' key");
+        };
+        let Ok(on) = on_event.parse() else {
+            bail!("match 'on' must be one of add, remove, change, bind, unbind, all, startup, shutdown or reload, not '{on_event}'");
         };
 
+        let m = Match::new(on).with_yaml(yaml)?;
+        if on.is_lifecycle() && m.needs_nodes() {
+            bail!("'on: {on}' rules can't match child nodes (i.e. 'block')");
+        }
+        Ok(m)
+    }
+}
+
+impl Match {
+    /// Adds the devices, ports and other matchers in `yaml`, everything but
+    /// `on`
+    pub fn with_yaml(mut self, yaml: &Yaml) -> anyhow::Result<Self> {
+        let m = &mut self;
         if let Some(devices) = yaml["devices"].as_vec() {
             trace!("Loading devices: array");
             let mut to_ignore: Vec<String> = Vec::new();
             for d in devices {
                 if let Some(path) = d["include_devices"].as_str() {
                     debug!(path = ?path, "Including devices from path");
-                    let file = File::open(path).unwrap();
-                    let mut devs: UsbDevices = serde_yaml::from_reader(file).unwrap();
-                    m.devices.append(&mut devs.devices);
+                    let mut devs = include(path, &mut m.includes)?.devices();
+                    m.devices.append(&mut devs);
                 } else if let Some(path) = d["exclude_devices"].as_str() {
                     debug!(path = ?path, "Excluding devices from path");
-                    let file = File::open(path).unwrap();
-                    let mut devs: UsbDevices = serde_yaml::from_reader(file).unwrap();
+                    let mut devs = include(path, &mut m.includes)?.devices();
                     let pre = m.devices.len();
-                    let num_devices = devs.devices.len();
+                    let num_devices = devs.len();
                     trace!(%pre, %num_devices);
                     // Add the devices to be able to match against their info
-                    m.devices.append(&mut devs.devices);
+                    m.devices.append(&mut devs);
                     for i in pre..(pre + num_devices) {
                         m.ignore_devices.push(i);
                     }
                 } else if d["name"].as_str().is_some() {
                     debug!(name = ?d, "Including device inline");
-                    m.devices.push(UsbDevice::from(d));
+                    m.devices.push(UsbDevice::try_from(d)?);
                 } else if let Some(name) = d.as_str() {
                     debug!(name = ?d, "Including device by name");
                     if name.starts_with('!') {
@@ -135,7 +332,7 @@ impl<'a> From<&'a Yaml> for Match {
                         m.devices.push(UsbDevice::new(name));
                     }
                 } else {
-                    cli_bail!("failed to pares YAML for Match; missing 'devices' key");
+                    bail!("'devices' must be names, inline devices, 'include_devices' or 'exclude_devices'");
                 }
             }
             for ignore_dev in to_ignore.into_iter() {
@@ -155,26 +352,169 @@ impl<'a> From<&'a Yaml> for Match {
 
         if let Some(ports) = yaml["ports"].as_vec() {
             trace!("Loading ports: array");
-            for p in ports {
-                if let Some(path) = p["include_ports"].as_str() {
-                    debug!(path = ?path, "Including port from path");
-                    let file = File::open(path).unwrap();
-                    let mut ports: UsbPorts = serde_yaml::from_reader(file).unwrap();
-                    debug!(ports = ?ports, "Found ports");
-                    m.ports.append(&mut ports.ports);
-                } else if p["name"].as_str().is_some() {
-                    debug!(name = ?p, "Including port inline");
-                    m.ports.push(UsbPort::from(p));
-                } else if let Some(name) = p.as_str() {
-                    debug!(name = ?p, "Including port by name");
-                    m.ports.push(UsbPort::new(name));
-                    // @TODO: will need to handle lookup of name / merge
-                } else {
-                    cli_bail!("failed to parse YAML for Match; missing 'include_ports' key");
+            m.ports
+                .append(&mut ports_from_yaml(ports, &mut m.includes)?);
+        }
+
+        match &yaml["interfaces"] {
+            Yaml::Array(list) => {
+                for i in list {
+                    m.interfaces.push(InterfaceMatch::try_from(i)?);
                 }
             }
+            Yaml::BadValue => (),
+            single => m.interfaces.push(InterfaceMatch::try_from(single)?),
+        }
+
+        for subsystem in NODE_SUBSYSTEMS {
+            let any_of = match &yaml[*subsystem] {
+                Yaml::BadValue => continue,
+                Yaml::Array(list) => list.iter().map(yaml_map).collect::<anyhow::Result<_>>(),
+                // `block: {}` matches any block device
+                single => yaml_map(single).map(|props| vec![props]),
+            }
+            .with_context(|| format!("'{subsystem}' is invalid"))?;
+            m.nodes.push(NodeMatch {
+                subsystem: subsystem.to_string(),
+                any_of,
+            });
+        }
+
+        match &yaml["wait"] {
+            Yaml::BadValue => (),
+            Yaml::Integer(n) if *n >= 0 => m.wait = Some(*n as u64),
+            _ => bail!("'wait' must be a number of seconds"),
+        }
+
+        Ok(self)
+    }
+}
+
+/// Loads an `include_devices` or `include_ports` file
+fn include(path: &str, includes: &mut Vec<PathBuf>) -> anyhow::Result<UsbInventory> {
+    includes.push(Path::new(path).into());
+    UsbInventory::from_path(path).with_context(|| format!("failed to include '{path}'"))
+}
+
+/// Swaps ports given only by name for the loaded definitions with the same
+/// name
+pub fn resolve_ports(ports: &mut [UsbPort], known: &[UsbPort]) {
+    for port in ports.iter_mut().filter(|p| p.is_empty()) {
+        if let Some(found) = known.iter().find(|k| k.name == port.name) {
+            debug!(port = %port, "Resolved port by name");
+            *port = found.clone();
         }
+    }
+}
+
+/// Ports listed in a rule; by name, inline, or `include_ports`, adding any
+/// included files to `includes`
+pub fn ports_from_yaml(
+    ports: &[Yaml],
+    includes: &mut Vec<PathBuf>,
+) -> anyhow::Result<Vec<UsbPort>> {
+    let mut ret = Vec::new();
+    for p in ports {
+        if let Some(path) = p["include_ports"].as_str() {
+            debug!(path = ?path, "Including port from path");
+            let mut ports = include(path, includes)?.ports();
+            debug!(ports = ?ports, "Found ports");
+            ret.append(&mut ports);
+        } else if p["name"].as_str().is_some() {
+            debug!(name = ?p, "Including port inline");
+            ret.push(UsbPort::try_from(p)?);
+        } else if let Some(name) = p.as_str() {
+            debug!(name = ?p, "Including port by name");
+            ret.push(UsbPort::new(name));
+            // @TODO: will need to handle lookup of name / merge
+        } else {
+            bail!("'ports' must be names, inline ports or 'include_ports'");
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+    use crate::usb::DeviceNode;
+
+    fn hid_keyboard() -> UsbDevice {
+        UsbDevice::new("kbd").with_interfaces(UsbInterface::parse_id_usb_interfaces(":030101:"))
+    }
+
+    fn parse(s: &str) -> Match {
+        Match::try_from(&YamlLoader::load_from_str(s).unwrap()[0]).unwrap()
+    }
+
+    #[test]
+    fn interfaces_contains_class() {
+        let m = parse("on: add\ninterfaces:\n  contains_class: 03\n");
+        let storage = UsbDevice::new("disk")
+            .with_interfaces(UsbInterface::parse_id_usb_interfaces(":080650:"));
+
+        assert!(m.matches_interfaces(&hid_keyboard()));
+        assert!(!m.matches_interfaces(&storage));
+        assert!(!m.matches_interfaces(&UsbDevice::new("unknown")));
+    }
+
+    #[test]
+    fn interfaces_list_any() {
+        let m = parse(
+            "on: add\ninterfaces:\n  - contains_class: e0\n  - contains_class: 03\n    contains_protocol: 01\n",
+        );
+
+        assert!(m.matches_interfaces(&hid_keyboard()));
+    }
+
+    #[test]
+    fn nodes_block_label() {
+        let m = parse("on: add\nblock:\n  ID_FS_LABEL: BACKUP\nwait: 30\n");
+        let node = |label: &str| DeviceNode {
+            sysname: "sdb1".into(),
+            properties: [
+                ("DEVNAME".into(), "/dev/sdb1".into()),
+                ("ID_FS_LABEL".into(), label.into()),
+            ]
+            .into(),
+        };
+
+        assert!(m.needs_nodes());
+        assert_eq!(m.node_subsystems(), ["block"]);
+        assert_eq!(m.wait(), Duration::from_secs(30));
+        assert!(m.matches_nodes(&[("block".into(), vec![node("BACKUP")])].into()));
+        assert!(!m.matches_nodes(&[("block".into(), vec![node("OTHER")])].into()));
+        assert!(!m.matches_nodes(&DeviceNodes::new()));
+    }
+
+    #[test]
+    fn nodes_empty_matches_any() {
+        let m = parse("on: add\ntty: {}\n");
+        let tty = DeviceNode {
+            sysname: "ttyUSB0".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(m.wait(), DEFAULT_NODE_WAIT);
+        assert!(m.matches_nodes(&[("tty".into(), vec![tty])].into()));
+        assert!(!parse("on: add\n").needs_nodes());
+    }
+
+    #[test]
+    fn no_interfaces_matches_all() {
+        let m = parse("on: add\n");
+
+        assert!(m.matches_interfaces(&UsbDevice::new("unknown")));
+    }
+
+    #[test]
+    fn on_all_is_literal() {
+        let m = parse("on: all\n");
 
-        m
+        assert!(m.matches_usb_event(&UsbEvent::All));
+        assert!(!m.matches_usb_event(&UsbEvent::Add));
+        assert!(!m.matches_usb_event(&UsbEvent::Remove));
     }
 }
@@ -0,0 +1,303 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tracing::{span, trace, Level};
+use yaml_rust::Yaml;
+
+use super::r#match::{ports_from_yaml, resolve_ports, Match};
+use crate::usb::{UsbDevice, UsbEvent, UsbPort};
+
+/// What has to be true of the attached devices a state rule is about
+#[derive(Serialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// None of them are attached
+    Missing,
+    /// More than this many are attached
+    MoreThan(usize),
+    /// At least one is attached to one of these ports
+    InPorts(Vec<UsbPort>),
+}
+
+/// Which changes of the condition run the rule's command
+#[derive(Serialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    /// The condition became true
+    #[default]
+    True,
+    /// The condition became false again
+    False,
+    Both,
+}
+
+/// Matches on what's attached at the moment rather than on a single event,
+/// i.e. "the scanner has been missing from 'front-left' for 30 seconds"
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct StateMatch {
+    /// Which attached devices the condition is about; all of them when no
+    /// devices or ports are given
+    select: Match,
+    condition: Condition,
+    /// Seconds the condition must hold before it counts as true
+    #[serde(rename = "for", skip_serializing_if = "Option::is_none")]
+    hold: Option<u64>,
+    on: Transition,
+    /// Files the `in_ports` were loaded from
+    #[serde(skip)]
+    includes: Vec<PathBuf>,
+}
+
+impl StateMatch {
+    /// The attached devices the condition is about; for `in_ports` only those
+    /// in one of the ports
+    pub fn relevant<'a>(
+        &self,
+        attached: &'a [(UsbPort, UsbDevice)],
+    ) -> Vec<&'a (UsbPort, UsbDevice)> {
+        attached
+            .iter()
+            .filter(|(port, device)| {
+                self.select.matches_port(port)
+                    && self.select.matches_device(device)
+                    && self.select.matches_interfaces(device)
+            })
+            .filter(|(port, _)| match &self.condition {
+                Condition::InPorts(ports) => ports.contains(port),
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Whether the condition holds given the [`StateMatch::relevant`] devices
+    pub fn holds(&self, relevant: &[&(UsbPort, UsbDevice)]) -> bool {
+        let ret = match self.condition {
+            Condition::Missing => relevant.is_empty(),
+            Condition::MoreThan(n) => relevant.len() > n,
+            Condition::InPorts(_) => !relevant.is_empty(),
+        };
+        trace!(condition = ?self.condition, relevant = %relevant.len(), returning = ?ret, "fn holds");
+        ret
+    }
+
+    /// Files the devices and ports were loaded from
+    pub fn includes(&self) -> impl Iterator<Item = &PathBuf> {
+        self.select.includes().iter().chain(&self.includes)
+    }
+
+    /// How long the condition must hold before it counts as true
+    pub fn hold(&self) -> Duration { Duration::from_secs(self.hold.unwrap_or(0)) }
+
+    /// Whether the condition becoming `active` runs the command
+    pub fn fires_on(&self, active: bool) -> bool {
+        match self.on {
+            Transition::True => active,
+            Transition::False => !active,
+            Transition::Both => true,
+        }
+    }
+
+    /// Swaps devices and ports given only by name for the loaded definitions
+    /// with the same name
+    pub fn resolve_names(&mut self, devices: &[UsbDevice], ports: &[UsbPort]) {
+        self.select.resolve_names(devices, ports);
+        if let Condition::InPorts(in_ports) = &mut self.condition {
+            resolve_ports(in_ports, ports);
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a Yaml> for StateMatch {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let span = span!(Level::TRACE, "fn TryFrom::<Yaml>");
+        let _enter = span.enter();
+
+        let select = Match::new(UsbEvent::All).with_yaml(yaml)?;
+        if select.needs_nodes() {
+            bail!("state rules can't match child nodes (i.e. 'block'), only event rules can");
+        }
+
+        let mut conditions = Vec::new();
+        let mut includes = Vec::new();
+        if yaml["missing"].as_bool() == Some(true) {
+            conditions.push(Condition::Missing);
+        }
+        match &yaml["more_than"] {
+            Yaml::BadValue => (),
+            Yaml::Integer(n) if *n >= 0 => conditions.push(Condition::MoreThan(*n as usize)),
+            _ => {
+                bail!("state 'more_than' must be a number of devices")
+            }
+        }
+        match &yaml["in_ports"] {
+            Yaml::BadValue => (),
+            Yaml::Array(ports) => {
+                conditions.push(Condition::InPorts(ports_from_yaml(ports, &mut includes)?))
+            }
+            _ => bail!("state 'in_ports' must be a list of ports"),
+        }
+        if conditions.len() != 1 {
+            bail!("state requires exactly one of 'missing: true', 'more_than' or 'in_ports'");
+        }
+
+        let hold = match &yaml["for"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) if *n >= 0 => Some(*n as u64),
+            _ => bail!("state 'for' must be a number of seconds"),
+        };
+
+        let on = match &yaml["on"] {
+            Yaml::BadValue | Yaml::Boolean(true) => Transition::True,
+            Yaml::Boolean(false) => Transition::False,
+            Yaml::String(s) if s == "both" => Transition::Both,
+            _ => bail!("state 'on' must be true, false or both"),
+        };
+
+        Ok(Self {
+            select,
+            condition: conditions.remove(0),
+            hold,
+            on,
+            includes,
+        })
+    }
+}
+
+/// Whether a state rule's condition is currently true, and since when it's
+/// held if it's still waiting out `for:`
+#[derive(Debug, Default, Clone)]
+pub struct Tracker {
+    since: Option<Instant>,
+    active: bool,
+}
+
+impl Tracker {
+    /// Records whether the condition `holds` at `now`, returning whether it's
+    /// now true if that changed
+    pub fn update(&mut self, holds: bool, hold: Duration, now: Instant) -> Option<bool> {
+        let active = if holds {
+            now >= *self.since.get_or_insert(now) + hold
+        } else {
+            self.since = None;
+            false
+        };
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(active)
+    }
+
+    /// When the condition will have held for `hold`, if it's waiting to
+    pub fn deadline(&self, hold: Duration) -> Option<Instant> {
+        self.since
+            .filter(|_| !self.active)
+            .map(|since| since + hold)
+    }
+}
+
+/// A device attached to a port
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Attached {
+    pub port: UsbPort,
+    pub device: UsbDevice,
+}
+
+/// What a state rule's command is run with
+///
+/// Placeholders in the command are filled in from this, i.e. `{{active}}` or
+/// `{{devices.0.device.ID_SERIAL}}`.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct StateChange {
+    pub rule: String,
+    /// Whether the condition is now true
+    pub active: bool,
+    /// The attached devices the condition is about
+    pub devices: Vec<Attached>,
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+
+    fn parse(s: &str) -> StateMatch {
+        StateMatch::try_from(&YamlLoader::load_from_str(s).unwrap()[0]).unwrap()
+    }
+
+    fn attached(sysname: &str, serial: &str) -> (UsbPort, UsbDevice) {
+        let mut port = UsbPort::default();
+        port.set_property("sysname", sysname);
+        let mut device = UsbDevice::default();
+        device.set_property("ID_SERIAL_SHORT", serial);
+        (port, device)
+    }
+
+    #[test]
+    fn state_conditions() {
+        let scanners = [attached("2-1", "123"), attached("2-2", "456")];
+
+        let missing =
+            parse("devices:\n  - name: scanner\n    ID_SERIAL_SHORT: '789'\nmissing: true\n");
+        assert!(missing.holds(&missing.relevant(&scanners)));
+        let with_it = [attached("2-3", "789")];
+        assert!(!missing.holds(&missing.relevant(&with_it)));
+
+        let more_than = parse("more_than: 1\n");
+        assert!(more_than.holds(&more_than.relevant(&scanners)));
+        assert!(!more_than.holds(&more_than.relevant(&scanners[..1])));
+
+        let forbidden = parse("in_ports:\n  - name: back\n    sysname: 2-2\n");
+        let relevant = forbidden.relevant(&scanners);
+        assert!(forbidden.holds(&relevant));
+        assert_eq!(relevant[0].1.serial(), Some("456"));
+        assert!(!forbidden.holds(&forbidden.relevant(&scanners[..1])));
+    }
+
+    #[test]
+    fn state_resolve_names() {
+        let mut m = parse("devices: [scanner]\nports: [front-left]\nmissing: true\n");
+        let (mut port, mut device) = attached("2-1", "123");
+        let here = [(port.clone(), device.clone())];
+        assert!(m.holds(&m.relevant(&here)));
+
+        device.name = Some("scanner".into());
+        port.name = Some("front-left".into());
+        m.resolve_names(&[device], &[port]);
+        assert!(!m.holds(&m.relevant(&here)));
+    }
+
+    #[test]
+    fn tracker_hold() {
+        let hold = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut t = Tracker::default();
+
+        assert_eq!(t.update(true, hold, start), None);
+        assert_eq!(t.deadline(hold), Some(start + hold));
+        assert_eq!(t.update(true, hold, start + Duration::from_secs(10)), None);
+        assert_eq!(t.update(true, hold, start + hold), Some(true));
+        assert_eq!(t.deadline(hold), None);
+        assert_eq!(t.update(false, hold, start + hold), Some(false));
+
+        // Flapping restarts the wait
+        assert_eq!(t.update(true, hold, start + hold), None);
+        assert_eq!(t.update(false, hold, start + hold), None);
+        assert_eq!(t.deadline(hold), None);
+    }
+
+    #[test]
+    fn state_fires_on() {
+        assert!(parse("missing: true\n").fires_on(true));
+        assert!(!parse("missing: true\n").fires_on(false));
+        assert!(parse("missing: true\non: false\n").fires_on(false));
+        assert!(parse("missing: true\non: both\n").fires_on(false));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    env, fmt, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::rule::{rule_files, Rule};
+
+/// Whether we're running as root, when rules effectively run commands as root
+pub fn is_root() -> bool {
+    // SAFETY: geteuid(2) always succeeds and doesn't touch our memory
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// A file or directory someone other than its owner (or root) can change
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insecure {
+    /// What was being checked
+    pub path: PathBuf,
+    /// The file or directory at fault, which may be one above `path` or
+    /// where it leads
+    pub at: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for Insecure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.at == self.path {
+            write!(f, "{} {}", self.path.display(), self.reason)
+        } else {
+            write!(
+                f,
+                "{} {}, so {} isn't safe to load",
+                self.at.display(),
+                self.reason,
+                self.path.display()
+            )
+        }
+    }
+}
+
+/// Every file and directory loading `rules` from `paths` reads or runs: the
+/// rules files and directories, the files included by the rules and their
+/// `command_shell`s
+pub fn rules_paths(paths: &[PathBuf], rules: &[Rule]) -> Vec<PathBuf> {
+    let mut all = paths.to_vec();
+    // Unreadable directories fail to load anyway
+    all.extend(rule_files(paths).unwrap_or_default());
+    for rule in rules {
+        all.extend(rule.includes().into_iter().map(PathBuf::from));
+        all.extend(rule.command_shell.clone());
+    }
+    all.sort();
+    all.dedup();
+    all
+}
+
+/// Checks each of `paths` the way [`check`] does, returning every problem
+pub fn check_all(paths: &[PathBuf], owner: u32) -> Vec<Insecure> {
+    paths.iter().filter_map(|p| check(p, owner).err()).collect()
+}
+
+/// Checks that only `owner` (or root) can change `path`, or any directory
+/// above it
+///
+/// Both the path as given and where it leads after following symlinks are
+/// checked. Directories above `path` may be writable by others as long as
+/// they're sticky (i.e. `/tmp`), since then nobody else can replace what's in
+/// them.
+pub fn check(path: &Path, owner: u32) -> Result<(), Insecure> {
+    let insecure = |at: &Path, reason: String| Insecure {
+        path: path.into(),
+        at: at.into(),
+        reason,
+    };
+    let given = env::current_dir()
+        .map(|dir| dir.join(path))
+        .map_err(|e| insecure(path, format!("can't be checked; {e}")))?;
+    let canonical =
+        fs::canonicalize(path).map_err(|e| insecure(path, format!("can't be checked; {e}")))?;
+
+    for full in [given.clone(), canonical] {
+        for (i, at) in full.ancestors().enumerate() {
+            if let Some(reason) = problem(at, owner, i == 0)
+                .unwrap_or_else(|e| Some(format!("can't be checked; {e}")))
+            {
+                // Only blame something other than the path given when it's
+                // really something else
+                let at = if at == given { path } else { at };
+                return Err(insecure(at, reason));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What's wrong with `at` on its own, if anything
+fn problem(at: &Path, owner: u32, target: bool) -> io::Result<Option<String>> {
+    let meta = fs::symlink_metadata(at)?;
+    if meta.uid() != owner && meta.uid() != 0 {
+        return Ok(Some(format!("is owned by uid {}", meta.uid())));
+    }
+    // The permissions of symlinks themselves don't mean anything
+    if meta.file_type().is_symlink() {
+        return Ok(None);
+    }
+
+    let sticky = meta.is_dir() && meta.mode() & 0o1000 != 0;
+    if sticky && !target {
+        return Ok(None);
+    }
+    if meta.mode() & 0o002 != 0 {
+        return Ok(Some("is writable by everyone".into()));
+    }
+    if meta.mode() & 0o020 != 0 {
+        return Ok(Some("is writable by its group".into()));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn chmod(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn secure_check() {
+        let dir = TempDir::new("secure");
+        let rules = dir.join("rules.yml");
+        fs::write(&rules, "rules: []\n").unwrap();
+        chmod(&dir, 0o755);
+        chmod(&rules, 0o644);
+        let owner = fs::metadata(&dir).unwrap().uid();
+
+        assert_eq!(check(&rules, owner), Ok(()));
+        assert!(check(&dir.join("missing.yml"), owner)
+            .unwrap_err()
+            .reason
+            .starts_with("can't be checked"));
+
+        chmod(&rules, 0o664);
+        let err = check(&rules, owner).unwrap_err();
+        assert_eq!(err.at, rules);
+        assert_eq!(err.reason, "is writable by its group");
+        chmod(&rules, 0o644);
+
+        // Anyone could swap the file for another in a world writable
+        // directory, unless it's sticky
+        chmod(&dir, 0o777);
+        let err = check(&rules, owner).unwrap_err();
+        assert_eq!(err.at, *dir);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is writable by everyone, so {} isn't safe to load",
+                dir.display(),
+                rules.display()
+            )
+        );
+        chmod(&dir, 0o1777);
+        assert_eq!(check(&rules, owner), Ok(()));
+        assert!(check(&dir, owner).is_err());
+        chmod(&dir, 0o755);
+
+        // Where a symlink leads is checked too
+        let elsewhere = dir.join("elsewhere.yml");
+        fs::write(&elsewhere, "rules: []\n").unwrap();
+        chmod(&elsewhere, 0o666);
+        let link = dir.join("link.yml");
+        symlink(&elsewhere, &link).unwrap();
+        assert_eq!(check(&link, owner).unwrap_err().at, elsewhere);
+    }
+}
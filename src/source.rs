@@ -0,0 +1,117 @@
+use std::{collections::VecDeque, fs, path::Path, pin::Pin};
+
+use anyhow::bail;
+use serde::Deserialize;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    listener::{monitor, UdevFilter},
+    udev::UdevEvent,
+    usb::{read_docs, UsbEvent},
+};
+
+/// Where interactive commands read udev events from
+///
+/// Commands take events one at a time rather than through the broadcast
+/// channel `run` and `listen` use, so a recorded list of events can stand in
+/// for real hardware.
+pub enum EventSource {
+    Udev(Pin<Box<dyn Stream<Item = UdevEvent>>>),
+    /// Previously recorded events, played back in order
+    Replay(VecDeque<UdevEvent>),
+}
+
+impl EventSource {
+    /// Events from the udev monitor which `filter` allows
+    pub fn udev(filter: UdevFilter) -> anyhow::Result<Self> {
+        let subsystems = filter.subsystems.clone();
+        let events = monitor(&subsystems, move |e| filter.allows(e))?
+            .filter_map(|e| e.ok().map(UdevEvent::from));
+
+        Ok(Self::Udev(Box::pin(events)))
+    }
+
+    pub fn replay<I: IntoIterator<Item = UdevEvent>>(events: I) -> Self {
+        Self::Replay(events.into_iter().collect())
+    }
+
+    /// Replays the events in a YAML document stream or NDJSON file, such as
+    /// the output of `usbwatch ctl subscribe --format ndjson`
+    ///
+    /// Records other than events, such as `rule_fired`, are skipped.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let mut events = Vec::new();
+        for record in read_docs(&fs::read_to_string(path)?)? {
+            match record {
+                Recorded::Event(event) => events.push(*event),
+                Recorded::Other { kind } if kind == "event" => bail!("invalid event record"),
+                Recorded::Other { .. } => (),
+            }
+        }
+        Ok(Self::replay(events))
+    }
+
+    /// The next event, or `None` once a replay runs out
+    pub async fn next(&mut self) -> Option<UdevEvent> {
+        match self {
+            Self::Udev(events) => events.next().await,
+            Self::Replay(events) => events.pop_front(),
+        }
+    }
+
+    /// The next time a device is plugged in
+    pub async fn next_add(&mut self) -> Option<UdevEvent> {
+        while let Some(event) = self.next().await {
+            if event.event_kind == UsbEvent::Add {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+/// A record in a file of events
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Recorded {
+    Event(Box<UdevEvent>),
+    /// Anything else `ctl subscribe` streams
+    Other {
+        kind: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        control::{Notification, RuleFired, Trigger},
+        testing::TempDir,
+        usb::{UsbDevice, UsbPort},
+    };
+
+    #[tokio::test]
+    async fn replay_subscribe_output() {
+        let event = UdevEvent {
+            event_kind: UsbEvent::Add,
+            device: UsbDevice::new("key"),
+            port: UsbPort::new("front"),
+            nodes: Default::default(),
+        };
+        let lines = [
+            Notification::Event(event.clone()),
+            Notification::RuleFired(RuleFired {
+                rule: "mount".into(),
+                trigger: Trigger::Event(event.clone()),
+            }),
+        ]
+        .map(|n| serde_json::to_string(&n).unwrap());
+        let dir = TempDir::new("source");
+        let path = dir.join("events.ndjson");
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let mut source = EventSource::from_path(&path).unwrap();
+        assert_eq!(source.next().await, Some(event));
+        assert_eq!(source.next().await, None);
+    }
+}
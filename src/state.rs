@@ -1,15 +1,17 @@
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    path::Path,
+    mem,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
+use anyhow::Context;
 use tracing::{debug, info, span, Level};
-use yaml_rust::YamlLoader;
 
 use crate::{
-    rule::{Rule, Rules},
-    usb::{UsbDevice, UsbDevices, UsbPort, UsbPorts},
+    authorize::Authorize,
+    rule::{Attached, Lifecycle, Rule, Rules, StateChange, Tracker},
+    usb::{UsbDevice, UsbEvent, UsbInventory, UsbPort},
 };
 
 #[derive(Default)]
@@ -22,48 +24,260 @@ pub struct State {
     // Device->Port
     rev_slot_map: HashMap<usize, usize>,
     pub rules: Vec<Rule>,
+    /// Every device plugged in right now, and where, for state rules
+    attached: Vec<(UsbPort, UsbDevice)>,
+    /// How each rule's state condition is doing, by rule index
+    trackers: Vec<Tracker>,
+    /// The only devices authorized when the policy is `default-deny`
+    allowed: Option<Vec<UsbDevice>>,
 }
 
 impl State {
     pub fn new() -> Self { Self::default() }
 
-    pub fn devices_from_file<P: AsRef<Path>>(&mut self, path: P) {
-        let span = span!(Level::TRACE, "fn devices_from_file", file = ?path.as_ref());
+    pub fn devices_from_file<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let span = span!(Level::TRACE, "fn devices_from_file", file = ?path);
         let _enter = span.enter();
 
-        let file = File::open(path).unwrap();
-        let devices: UsbDevices = serde_yaml::from_reader(file).unwrap();
-        info!(num_devs= %devices.devices.len(), "Found Devices");
-        for device in devices.devices.into_iter() {
+        let devices = UsbInventory::from_path(path)
+            .with_context(|| format!("invalid devices in {}", path.display()))?
+            .devices();
+        info!(num_devs= %devices.len(), "Found Devices");
+        for device in devices.into_iter() {
             debug!(device = %device, "Adding Device");
             self.add_device(device);
         }
+        Ok(())
     }
 
-    pub fn ports_from_file<P: AsRef<Path>>(&mut self, path: P) {
-        let span = span!(Level::TRACE, "fn ports_from_file", file = ?path.as_ref());
+    pub fn ports_from_file<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let span = span!(Level::TRACE, "fn ports_from_file", file = ?path);
         let _enter = span.enter();
 
-        let file = File::open(path).unwrap();
-        let ports: UsbPorts = serde_yaml::from_reader(file).unwrap();
-        info!(num_ports= %ports.ports.len(), "Found Ports");
-        for port in ports.ports.into_iter() {
+        let ports = UsbInventory::from_path(path)
+            .with_context(|| format!("invalid ports in {}", path.display()))?
+            .ports();
+        info!(num_ports= %ports.len(), "Found Ports");
+        for port in ports.into_iter() {
             debug!(port = %port, "Adding Port");
             self.add_port(port);
         }
+        Ok(())
     }
 
-    pub fn rules_from_file<P: AsRef<Path>>(&mut self, path: P) {
-        let span = span!(Level::TRACE, "fn rules_from_file", file = ?path.as_ref());
+    /// Loads the rules from each of `paths`, files or directories of them
+    pub fn rules_from_paths(&mut self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        let span = span!(Level::TRACE, "fn rules_from_paths", paths = ?paths);
         let _enter = span.enter();
 
-        let buf = fs::read_to_string(path).unwrap();
-        let rules = Rules::from(&YamlLoader::load_from_str(&buf).unwrap()[0]);
+        let rules = Rules::from_paths(paths)?;
         info!(num_rules= %rules.rules.len(), "Found Rules");
-        for rule in rules.rules.into_iter() {
-            debug!(ruel = ?rule.name, "Adding Rule");
+        for mut rule in rules.rules.into_iter() {
+            debug!(rule = ?rule.name, "Adding Rule");
+            if let Some(state) = rule.state_mut() {
+                state.resolve_names(&self.devices, &self.ports);
+            }
             self.rules.push(rule);
         }
+        Ok(())
+    }
+
+    /// Denies every device not in the devices files at `paths`, see
+    /// [`State::authorization`]
+    pub fn allow_from_paths(&mut self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        let span = span!(Level::TRACE, "fn allow_from_paths", paths = ?paths);
+        let _enter = span.enter();
+
+        let allowed = self.allowed.get_or_insert_with(Vec::new);
+        for path in paths {
+            let mut devices = UsbInventory::from_path(path)
+                .with_context(|| format!("invalid devices in {}", path.display()))?
+                .devices();
+            info!(num_devs= %devices.len(), file = ?path, "Found Allowed Devices");
+            allowed.append(&mut devices);
+        }
+        Ok(())
+    }
+
+    /// Whether the policy allows `device`, or `None` without a policy
+    ///
+    /// Hubs are always allowed, otherwise allowed devices plugged in through
+    /// one would be cut off along with it.
+    pub fn authorization(&self, device: &UsbDevice) -> Option<Authorize> {
+        let allowed = self.allowed.as_ref()?;
+        if device.is_hub() || allowed.contains(device) {
+            Some(Authorize::Allow)
+        } else {
+            Some(Authorize::Deny)
+        }
+    }
+
+    /// How each attached device should be authorized, i.e. at startup or
+    /// after a reload
+    ///
+    /// The policy is applied first, and then any `add` rules which authorize
+    /// the device, just like when it's plugged in. Along with each is the name
+    /// of the rule it's down to, if it isn't the policy.
+    pub fn authorizations(&self) -> Vec<(UsbPort, Authorize, Option<&str>)> {
+        self.attached
+            .iter()
+            .filter_map(|(port, device)| {
+                let by_rule = self.rules.iter().rev().find(|r| {
+                    r.authorize.is_some()
+                        && r.on() == Some(UsbEvent::Add)
+                        && r.matches_attached(port, device)
+                });
+                match by_rule {
+                    Some(rule) => Some((port.clone(), rule.authorize?, Some(rule.name.as_str()))),
+                    None => Some((port.clone(), self.authorization(device)?, None)),
+                }
+            })
+            .collect()
+    }
+
+    /// Takes the rules, devices and ports of `fresh`, a `State` loaded off to
+    /// the side, keeping track of what's attached
+    ///
+    /// State rules which haven't changed keep how long their condition has
+    /// held, so reloading doesn't run their commands again.
+    pub fn replace_config(&mut self, fresh: State) {
+        let trackers = fresh
+            .rules
+            .iter()
+            .map(|rule| {
+                self.rules
+                    .iter()
+                    .position(|r| r == rule)
+                    .and_then(|i| self.trackers.get(i).cloned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        let attached = mem::take(&mut self.attached);
+
+        *self = fresh;
+        self.trackers = trackers;
+        for (port, device) in attached {
+            self.add_and_slot_device(device.clone(), port.clone());
+            self.attached.push((port, device));
+        }
+    }
+
+    /// How many devices are attached and rules loaded, i.e. for systemd
+    pub fn status(&self) -> String {
+        format!(
+            "{} devices attached, {} rules loaded",
+            self.attached.len(),
+            self.rules.len()
+        )
+    }
+
+    /// Records that `device` is plugged into `port`
+    ///
+    /// Root hubs are part of the machine rather than plugged in, and are left
+    /// out.
+    pub fn attach(&mut self, port: UsbPort, device: UsbDevice) {
+        if port.is_root_hub() || device.is_empty() {
+            return;
+        }
+        self.detach(&port);
+        self.attached.push((port, device));
+    }
+
+    /// Records that whatever was plugged into `port` is gone
+    pub fn detach(&mut self, port: &UsbPort) {
+        self.attached
+            .retain(|(p, _)| match (p.syspath(), port.syspath()) {
+                (Some(a), Some(b)) => a != b,
+                _ => p != port,
+            });
+    }
+
+    /// The rules triggered by `event` from the daemon itself, along with what
+    /// to run each of their commands with
+    pub fn lifecycle(&self, event: UsbEvent) -> Vec<(Rule, Lifecycle)> {
+        let span = span!(Level::TRACE, "fn lifecycle", ?event);
+        let _enter = span.enter();
+
+        let mut triggered = Vec::new();
+        for rule in self.rules.iter().filter(|r| r.on() == Some(event)) {
+            let matching = self
+                .attached
+                .iter()
+                .filter(|(port, device)| rule.matches_attached(port, device))
+                .map(|(port, device)| Attached {
+                    port: port.clone(),
+                    device: device.clone(),
+                });
+            if rule.per_device() {
+                for attached in matching {
+                    debug!(rule = ?rule.name, device = %attached.device, "Triggered for device");
+                    let lifecycle = Lifecycle {
+                        event,
+                        attached: Some(attached),
+                        devices: None,
+                    };
+                    triggered.push((rule.clone(), lifecycle));
+                }
+            } else {
+                debug!(rule = ?rule.name, "Triggered");
+                let lifecycle = Lifecycle {
+                    event,
+                    attached: None,
+                    devices: Some(matching.collect()),
+                };
+                triggered.push((rule.clone(), lifecycle));
+            }
+        }
+        triggered
+    }
+
+    /// Checks each state rule against what's attached at `now`, returning
+    /// the rules whose condition changed in a way that runs their command
+    pub fn evaluate(&mut self, now: Instant) -> Vec<(Rule, StateChange)> {
+        let span = span!(Level::TRACE, "fn evaluate");
+        let _enter = span.enter();
+
+        self.trackers.resize(self.rules.len(), Tracker::default());
+        let mut changes = Vec::new();
+        for (rule, tracker) in self.rules.iter().zip(self.trackers.iter_mut()) {
+            let Some(state) = rule.state() else {
+                continue;
+            };
+            let relevant = state.relevant(&self.attached);
+            let Some(active) = tracker.update(state.holds(&relevant), state.hold(), now) else {
+                continue;
+            };
+            debug!(rule = ?rule.name, ?active, "State condition changed");
+            if state.fires_on(active) {
+                let devices = relevant
+                    .into_iter()
+                    .map(|(port, device)| Attached {
+                        port: port.clone(),
+                        device: device.clone(),
+                    })
+                    .collect();
+                changes.push((
+                    rule.clone(),
+                    StateChange {
+                        rule: rule.name.clone(),
+                        active,
+                        devices,
+                    },
+                ));
+            }
+        }
+        changes
+    }
+
+    /// The soonest a state rule waiting out its `for:` could become true
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.rules
+            .iter()
+            .zip(self.trackers.iter())
+            .filter_map(|(rule, tracker)| tracker.deadline(rule.state()?.hold()))
+            .min()
     }
 
     pub fn add_port(&mut self, port: UsbPort) {
@@ -92,10 +306,17 @@ impl State {
         self.devices.push(device);
     }
 
-    pub fn add_and_slot_device(&mut self, device: UsbDevice, port: UsbPort) {
+    /// Records `device` as plugged into `port`
+    ///
+    /// Both are stripped of what changes every time they're plugged in (i.e.
+    /// the device number), so plugging the same device in again doesn't add
+    /// it a second time.
+    pub fn add_and_slot_device(&mut self, mut device: UsbDevice, mut port: UsbPort) {
         let span = span!(Level::TRACE, "fn add_and_slot_device", device = %device, port = %port);
         let _enter = span.enter();
 
+        device.strip_volatile();
+        port.strip_volatile();
         self.add_port(port.clone());
         self.add_device(device.clone());
 
@@ -125,7 +346,9 @@ impl State {
                         );
                         *self.rev_slot_map.entry(j).or_insert(i) = i;
                         debug!("Activating device index {}", j);
-                        self.active_devices.push(j);
+                        if !self.active_devices.contains(&j) {
+                            self.active_devices.push(j);
+                        }
 
                         debug!("Returning");
                         break;
@@ -135,10 +358,11 @@ impl State {
         }
     }
 
-    pub fn rm_and_unslot_device(&mut self, device: UsbDevice) {
+    pub fn rm_and_unslot_device(&mut self, mut device: UsbDevice) {
         let span = span!(Level::TRACE, "fn rm_and_unslot_device", device = %device);
         let _enter = span.enter();
 
+        device.strip_volatile();
         for (i, d) in self.devices.iter().enumerate() {
             debug!(i=i, device = %d, "Iter devices");
             if d == &device {
@@ -168,3 +392,187 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+    use crate::{testing::TempDir, usb::UsbInterface};
+
+    #[test]
+    fn replugging_adds_device_once() {
+        let mut state = State::new();
+        let mut port = UsbPort::default();
+        port.set_property("sysname", "1-1");
+        for devnum in [5, 6, 7] {
+            let yaml = YamlLoader::load_from_str(&format!(
+                "name: stick\nID_VENDOR_ID: '0781'\nDEVNUM: '00{devnum}'\n\
+                 DEVNAME: /dev/bus/usb/001/00{devnum}\n\
+                 properties: {{DEVPATH: /devices/usb1/1-1/{devnum}}}\n\
+                 attributes: {{urbnum: '{devnum}'}}\n"
+            ))
+            .unwrap();
+            let device = UsbDevice::try_from(&yaml[0]).unwrap();
+
+            state.add_and_slot_device(device.clone(), port.clone());
+            assert_eq!(state.devices.len(), 1);
+            assert_eq!(state.active_devices, [0]);
+            state.rm_and_unslot_device(device);
+            assert!(state.active_devices.is_empty());
+        }
+    }
+
+    #[test]
+    fn state_rules_fire_on_change() {
+        let yaml = YamlLoader::load_from_str(
+            "name: no scanner\nstate:\n  devices:\n    - name: scanner\n      ID_MODEL_ID: '0001'\n  missing: true\n  for: 30\n  on: both\ncommand: 'true'\n",
+        )
+        .unwrap();
+        let mut state = State::new();
+        state.rules.push(Rule::try_from(&yaml[0]).unwrap());
+
+        let mut port = UsbPort::default();
+        port.set_property("syspath", "/sys/devices/pci0000:00/usb2/2-1");
+        let mut scanner = UsbDevice::default();
+        scanner.set_property("ID_MODEL_ID", "0001");
+
+        let start = Instant::now();
+        assert!(state.evaluate(start).is_empty());
+        assert_eq!(state.next_deadline(), Some(start + Duration::from_secs(30)));
+
+        let changes = state.evaluate(start + Duration::from_secs(30));
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].1.active);
+
+        state.attach(port.clone(), scanner);
+        let changes = state.evaluate(start + Duration::from_secs(31));
+        assert!(!changes[0].1.active);
+        assert_eq!(changes[0].1.devices.len(), 1);
+
+        state.detach(&port);
+        assert!(state.evaluate(start + Duration::from_secs(32)).is_empty());
+        assert_eq!(state.next_deadline(), Some(start + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn replace_config_keeps_unchanged() {
+        let rules = |extra: &str| {
+            format!(
+                "rules:\n  - name: any\n    state: {{more_than: 0}}\n    command: 'true'\n{extra}"
+            )
+            .parse::<Rules>()
+            .unwrap()
+            .rules
+        };
+        let mut state = State::new();
+        state.rules = rules("");
+        let mut port = UsbPort::default();
+        port.set_property("sysname", "2-1");
+        let mut device = UsbDevice::default();
+        device.set_property("ID_MODEL_ID", "0001");
+        state.attach(port, device);
+
+        let now = Instant::now();
+        assert_eq!(state.evaluate(now).len(), 1);
+
+        let mut fresh = State::new();
+        fresh.rules = rules("  - name: many\n    state: {more_than: 0}\n    command: 'echo'\n");
+        state.replace_config(fresh);
+
+        // Only the new rule fires, and what's attached is still known
+        let changes = state.evaluate(now);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0.name, "many");
+        assert_eq!(changes[0].1.devices.len(), 1);
+    }
+
+    #[test]
+    fn lifecycle_rules() {
+        let mut state = State::new();
+        state.rules = "rules:\n  - name: each\n    match:\n      on: startup\n      devices: [{name: kiosk, ID_MODEL_ID: '0001'}]\n    command: 'true'\n  - name: once\n    match: {on: startup}\n    command: 'true'\n  - name: later\n    match: {on: shutdown}\n    command: 'true'\n"
+            .parse::<Rules>()
+            .unwrap()
+            .rules;
+        for (sysname, model) in [("2-1", "0001"), ("2-2", "0002"), ("2-3", "0001")] {
+            let mut port = UsbPort::default();
+            port.set_property("sysname", sysname);
+            let mut device = UsbDevice::default();
+            device.set_property("ID_MODEL_ID", model);
+            state.attach(port, device);
+        }
+
+        let triggered = state.lifecycle(UsbEvent::Startup);
+        let names: Vec<_> = triggered.iter().map(|(r, _)| r.name.as_str()).collect();
+        assert_eq!(names, ["each", "each", "once"]);
+        let port = &triggered[1].1.attached.as_ref().unwrap().port;
+        assert_eq!(port.sysname(), Some("2-3"));
+        assert_eq!(triggered[2].1.devices.as_ref().unwrap().len(), 3);
+
+        assert_eq!(state.lifecycle(UsbEvent::Reload).len(), 0);
+        assert_eq!(state.lifecycle(UsbEvent::Shutdown).len(), 1);
+    }
+
+    #[test]
+    fn policy_authorization() {
+        let dir = TempDir::new("allow");
+        let allowed = dir.join("allowed.yml");
+        std::fs::write(
+            &allowed,
+            "devices:\n  - name: keyboard\n    ID_VENDOR_ID: '046d'\n    ID_MODEL_ID: c31c\n",
+        )
+        .unwrap();
+
+        let mut keyboard = UsbDevice::default();
+        keyboard.set_property("ID_VENDOR_ID", "046d");
+        keyboard.set_property("ID_MODEL_ID", "c31c");
+        let mut stick = UsbDevice::default();
+        stick.set_property("ID_VENDOR_ID", "0781");
+        let mut hub = UsbDevice::default();
+        hub.set_property("ID_VENDOR_ID", "05e3");
+        hub.set_attribute("bDeviceClass", "09");
+        let hub = hub.with_interfaces(UsbInterface::parse_id_usb_interfaces(":090000:"));
+        // Claims to be a hub, but has a keyboard interface too
+        let bad_usb = hub
+            .clone()
+            .with_interfaces(UsbInterface::parse_id_usb_interfaces(":090000:030101:"));
+
+        let mut state = State::new();
+        assert_eq!(state.authorization(&stick), None);
+
+        state.allow_from_paths(&[allowed]).unwrap();
+        assert_eq!(state.authorization(&keyboard), Some(Authorize::Allow));
+        assert_eq!(state.authorization(&hub), Some(Authorize::Allow));
+        assert_eq!(state.authorization(&bad_usb), Some(Authorize::Deny));
+        assert_eq!(state.authorization(&stick), Some(Authorize::Deny));
+
+        // Rules which allow devices when they're added get the last word
+        state.rules = "rules:\n  - name: sandisk\n    match:\n      on: add\n      devices: [{name: sandisk, ID_VENDOR_ID: '0781'}]\n    authorize: allow\n"
+            .parse::<Rules>()
+            .unwrap()
+            .rules;
+        let mut other = UsbDevice::default();
+        other.set_property("ID_VENDOR_ID", "1234");
+        for (sysname, device) in [("1-1", keyboard.clone()), ("1-2", stick), ("1-3", other)] {
+            let mut port = UsbPort::default();
+            port.set_property("sysname", sysname);
+            state.attach(port, device);
+        }
+        let authorizations: Vec<_> = state
+            .authorizations()
+            .into_iter()
+            .map(|(_, a, _)| a)
+            .collect();
+        assert_eq!(
+            authorizations,
+            [Authorize::Allow, Authorize::Allow, Authorize::Deny]
+        );
+
+        // Without any devices files, nothing but hubs is allowed
+        let mut state = State::new();
+        state.allow_from_paths(&[]).unwrap();
+        assert_eq!(state.authorization(&keyboard), Some(Authorize::Deny));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+/// Tells systemd how the daemon is doing, for `Type=notify` services
+///
+/// Messages are datagrams of newline separated `KEY=VALUE` pairs sent to the
+/// socket in `NOTIFY_SOCKET`; see `sd_notify(3)`.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Sends to `addr`, a path or an abstract socket name starting with '@'
+    pub fn new(addr: &str) -> io::Result<Self> {
+        let addr = match addr.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(addr)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog: None,
+        })
+    }
+
+    /// The notifier for the socket systemd gave us, if any
+    ///
+    /// The variables systemd set are removed from the environment so the
+    /// commands rules run don't inherit them and report as us.
+    pub fn from_env() -> Option<Self> {
+        let addr = env::var("NOTIFY_SOCKET").ok();
+        let watchdog = watchdog_interval(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+
+        match Self::new(&addr?) {
+            Ok(notifier) => Some(Self {
+                watchdog,
+                ..notifier
+            }),
+            Err(err) => {
+                warn!(cause = %err, "Invalid NOTIFY_SOCKET; not notifying systemd");
+                None
+            }
+        }
+    }
+
+    /// How often to send [`Notifier::watchdog`] pings, when systemd wants
+    /// them
+    ///
+    /// Half of systemd's timeout, as `sd_watchdog_enabled(3)` recommends.
+    pub fn watchdog_interval(&self) -> Option<Duration> { self.watchdog.map(|w| w / 2) }
+
+    /// Startup (or a reload) is done and events are being handled
+    pub fn ready(&self) { self.send("READY=1") }
+
+    /// The rules are being reloaded; followed by [`Notifier::ready`]
+    pub fn reloading(&self) { self.send("RELOADING=1") }
+
+    /// One line description of what the daemon is up to
+    pub fn status(&self, status: &str) { self.send(&format!("STATUS={status}")) }
+
+    /// Tells systemd the daemon isn't stuck
+    pub fn watchdog(&self) { self.send("WATCHDOG=1") }
+
+    /// Shutting down
+    pub fn stopping(&self) { self.send("STOPPING=1") }
+
+    /// Failing to tell systemd something is never fatal; at worst it restarts
+    /// us or reports the wrong status
+    fn send(&self, msg: &str) {
+        debug!(msg = %msg, "Notifying systemd");
+        if let Err(err) = self.socket.send_to_addr(msg.as_bytes(), &self.addr) {
+            warn!(cause = %err, msg = %msg, "Failed to notify systemd");
+        }
+    }
+}
+
+/// Parses systemd's `WATCHDOG_USEC`, which only applies to us if
+/// `WATCHDOG_PID` is unset or our pid
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(process::id())) {
+        return None;
+    }
+    usec?
+        .parse()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn notify_messages() {
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(path.to_str().unwrap()).unwrap();
+        notifier.ready();
+        notifier.status("2 devices attached, 3 rules loaded");
+
+        let mut buf = [0; 256];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=2 devices attached, 3 rules loaded");
+    }
+
+    #[test]
+    fn notify_abstract() {
+        let name = format!("usbwatch-notify-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+
+        Notifier::new(&format!("@{name}")).unwrap().watchdog();
+
+        let mut buf = [0; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn notify_watchdog_interval() {
+        let me = process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some(&me)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
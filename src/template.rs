@@ -0,0 +1,221 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+use serde::Serialize;
+use serde_json::Value;
+
+/// A line of text with `{{path.to.field}}` placeholders
+///
+/// Placeholders are looked up in the serialized form of whatever is being
+/// rendered, so any field that appears in YAML/JSON output can be used. Nested
+/// fields and list items are separated by `.` (i.e. `{{device.ID_VENDOR_ID}}`
+/// or `{{devices.0.name}}`). Missing fields render as an empty string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Vec<String>),
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            if start != 0 {
+                parts.push(Part::Text(rest[..start].into()));
+            }
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                bail!(
+                    "unclosed '{{{{' in template at byte {}",
+                    s.len() - rest.len() + start
+                );
+            };
+            let path = after[..end].trim();
+            if path.is_empty() {
+                bail!("empty '{{{{}}}}' in template");
+            }
+            parts.push(Part::Field(path.split('.').map(Into::into).collect()));
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.into()));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+impl Template {
+    /// Renders the template against the serialized form of `value`
+    pub fn render<T: Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        Ok(self.render_value(&serde_json::to_value(value)?))
+    }
+
+    /// Renders the template into a shell command, with every field quoted so
+    /// that it's a single word whatever it contains
+    ///
+    /// Field values can come from the device itself (i.e. a filesystem label
+    /// or serial number), so they must never be able to add commands of their
+    /// own.
+    pub fn render_shell<T: Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        Ok(self.render_with(&serde_json::to_value(value)?, shell_quote))
+    }
+
+    /// Checks that every field is a whole word of the shell command, rather
+    /// than inside quotes of its own
+    ///
+    /// [`Template::render_shell`] quotes each field with `'`, which means
+    /// nothing inside `"..."`, so a value like `$(reboot)` would still be run
+    /// there.
+    pub fn check_shell(&self) -> anyhow::Result<()> {
+        let mut quote = None;
+        for part in &self.parts {
+            match part {
+                Part::Text(t) => {
+                    let mut chars = t.chars();
+                    while let Some(c) = chars.next() {
+                        match (quote, c) {
+                            (Some('\''), '\'') => quote = None,
+                            (Some('\''), _) => {}
+                            (_, '\\') => {
+                                chars.next();
+                            }
+                            (None, '\'' | '"') => quote = Some(c),
+                            (Some('"'), '"') => quote = None,
+                            _ => {}
+                        }
+                    }
+                }
+                Part::Field(path) if quote.is_some() => bail!(
+                    "'{{{{{}}}}}' is inside quotes; use it as a whole word, it's quoted already",
+                    path.join(".")
+                ),
+                Part::Field(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render_value(&self, value: &Value) -> String { self.render_with(value, str::to_owned) }
+
+    fn render_with(&self, value: &Value, field: fn(&str) -> String) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(t) => out.push_str(t),
+                Part::Field(path) => {
+                    let v = lookup(value, path).map_or(String::new(), |v| Display(v).to_string());
+                    out.push_str(&field(&v));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Quotes `s` as a single word for `sh`, i.e. `it's` becomes `'it'\''s'`
+pub fn shell_quote(s: &str) -> String { format!("'{}'", s.replace('\'', r"'\''")) }
+
+fn lookup<'a>(mut value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    for key in path {
+        value = match value {
+            Value::Object(map) => map.get(key)?,
+            Value::Array(list) => list.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Displays strings without quotes, and nulls as nothing
+struct Display<'a>(&'a Value);
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Null => Ok(()),
+            Value::String(s) => f.write_str(s),
+            v => write!(f, "{v}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn template_fields() {
+        let t: Template =
+            "{{event}} {{device.ID_VENDOR_ID}}:{{ device.ID_MODEL_ID }} {{port.sysname}}"
+                .parse()
+                .unwrap();
+        let v = json!({
+            "event": "add",
+            "device": { "ID_VENDOR_ID": "0781", "ID_MODEL_ID": "5583" },
+            "port": { "sysname": "2-1", "sysnum": 1 },
+        });
+
+        assert_eq!(t.render_value(&v), "add 0781:5583 2-1");
+    }
+
+    #[test]
+    fn template_missing_and_non_string() {
+        let t: Template = "[{{device.nope}}] {{port.sysnum}} {{list.1}}"
+            .parse()
+            .unwrap();
+        let v = json!({ "port": { "sysnum": 1 }, "list": ["a", "b"] });
+
+        assert_eq!(t.render_value(&v), "[] 1 b");
+    }
+
+    #[test]
+    fn template_no_fields() {
+        let t: Template = "plain text".parse().unwrap();
+
+        assert_eq!(t.render_value(&json!({})), "plain text");
+    }
+
+    #[test]
+    fn template_render_shell() {
+        let t: Template = "mount {{label}} /mnt/{{missing}}".parse().unwrap();
+
+        assert_eq!(
+            t.render_shell(&json!({ "label": "x; rm -rf / #" }))
+                .unwrap(),
+            "mount 'x; rm -rf / #' /mnt/''"
+        );
+        assert_eq!(
+            t.render_shell(&json!({ "label": "it's $(id)" })).unwrap(),
+            r"mount 'it'\''s $(id)' /mnt/''"
+        );
+    }
+
+    #[test]
+    fn template_check_shell() {
+        let ok = |s: &str| s.parse::<Template>().unwrap().check_shell().is_ok();
+
+        assert!(ok("echo plugged in at {{port.sysname}} > usb.log"));
+        assert!(ok(r#"echo "it's" {{a}} 'say "hi"' {{b}} \" {{c}}"#));
+        assert!(!ok(r#"echo "plugged in at {{port.sysname}}!""#));
+        assert!(!ok("echo 'at {{port.sysname}}'"));
+        assert!(!ok("echo \"a\nb {{c}}\""));
+    }
+
+    #[test]
+    fn template_unclosed() {
+        assert!("{{event} foo".parse::<Template>().is_err());
+        assert!("foo {{}}".parse::<Template>().is_err());
+    }
+}
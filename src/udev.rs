@@ -1,12 +1,17 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::usb::{UsbDevice, UsbEvent, UsbPort};
+use crate::usb::{DeviceNodes, UsbDevice, UsbEvent, UsbPort};
 
-#[derive(Clone, PartialEq, Debug, Serialize)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct UdevEvent {
+    #[serde(rename = "event")]
     pub event_kind: UsbEvent,
     pub device: UsbDevice,
     pub port: UsbPort,
+    /// Devices in other subsystems belonging to `device`, only looked up for
+    /// rules that match on them
+    #[serde(skip_serializing_if = "DeviceNodes::is_empty", default)]
+    pub nodes: DeviceNodes,
 }
 
 impl From<tokio_udev::Event> for UdevEvent {
@@ -16,6 +21,7 @@ impl From<tokio_udev::Event> for UdevEvent {
             event_kind: e.event_type().into(),
             device: UsbDevice::from(&d),
             port: UsbPort::from(&d),
+            nodes: DeviceNodes::new(),
         }
     }
 }
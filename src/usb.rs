@@ -1,22 +1,31 @@
 mod device;
+mod interface;
+mod node;
 mod port;
+mod topology;
 
-use std::result::Result as StdResult;
+use std::{collections::BTreeMap, ffi::OsStr, fs, path::Path, result::Result as StdResult};
 
+use anyhow::bail;
 use clap::ValueEnum;
 use serde::{
-    de::{self, Deserializer},
+    de::{self, DeserializeOwned, Deserializer},
     ser::Serializer,
     Deserialize, Serialize,
 };
 use strum::{Display, EnumString};
 use tokio_udev::EventType;
+use yaml_rust::Yaml;
 
 pub use device::{UsbDevice, UsbDevices};
+pub use interface::{yaml_code, UsbInterface};
+pub use node::{DeviceNode, DeviceNodes, NODE_SUBSYSTEMS};
 pub use port::{UsbPort, UsbPorts};
+pub use topology::{ChainPattern, Topology};
 
 #[derive(Default, EnumString, Display, ValueEnum, Copy, Clone, PartialEq, Eq, Debug, Serialize)]
 #[strum(ascii_case_insensitive, serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum UsbEvent {
     Add,
     Bind,
@@ -26,6 +35,25 @@ pub enum UsbEvent {
     Unknown,
     #[default]
     All,
+    /// The daemon started and has scanned what's attached
+    #[value(skip)]
+    Startup,
+    /// The daemon is shutting down
+    #[value(skip)]
+    Shutdown,
+    /// The rules were reloaded
+    #[value(skip)]
+    Reload,
+}
+
+impl UsbEvent {
+    /// Whether the event comes from the daemon itself rather than udev
+    pub fn is_lifecycle(self) -> bool {
+        matches!(
+            self,
+            UsbEvent::Startup | UsbEvent::Shutdown | UsbEvent::Reload
+        )
+    }
 }
 
 impl<'de> Deserialize<'de> for UsbEvent {
@@ -48,6 +76,142 @@ impl From<tokio_udev::EventType> for UsbEvent {
     }
 }
 
+/// A set of ports and/or devices
+///
+/// This is the shape of both the inventory files, and what `scan` and `listen`
+/// display, so that output can be saved and used as an inventory.
+#[derive(Default, Deserialize, Serialize, Debug, PartialEq)]
+pub struct UsbInventory {
+    /// The event that produced this inventory, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub event: Option<UsbEvent>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ports: Option<Vec<UsbPort>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub devices: Option<Vec<UsbDevice>>,
+}
+
+impl UsbInventory {
+    /// Loads every document in a YAML (or NDJSON) file into a single inventory
+    ///
+    /// Files written by `listen --output` are a stream of documents, one per
+    /// event, while hand written inventories are usually a single document.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let buf = fs::read_to_string(path)?;
+        Self::from_docs(&buf)
+    }
+
+    pub fn from_docs(buf: &str) -> anyhow::Result<Self> {
+        let mut inventory = Self::default();
+        for doc in read_docs::<Self>(buf)? {
+            inventory.merge(doc);
+        }
+        Ok(inventory)
+    }
+
+    fn merge(&mut self, other: Self) {
+        if let Some(mut ports) = other.ports {
+            self.ports.get_or_insert_with(Vec::new).append(&mut ports);
+        }
+        if let Some(mut devices) = other.devices {
+            self.devices
+                .get_or_insert_with(Vec::new)
+                .append(&mut devices);
+        }
+    }
+
+    /// Clears values that change each time a device is plugged in, see
+    /// [`UsbDevice::strip_volatile`]
+    pub fn strip_volatile(&mut self) {
+        for port in self.ports.iter_mut().flatten() {
+            port.strip_volatile();
+        }
+        for device in self.devices.iter_mut().flatten() {
+            device.strip_volatile();
+        }
+    }
+
+    pub fn ports(self) -> Vec<UsbPort> { self.ports.unwrap_or_default() }
+
+    pub fn devices(self) -> Vec<UsbDevice> { self.devices.unwrap_or_default() }
+}
+
+/// Reads every document in a YAML document stream, or every line of an NDJSON
+/// file (which is detected by every line being a JSON object)
+pub fn read_docs<T: DeserializeOwned>(buf: &str) -> anyhow::Result<Vec<T>> {
+    if is_ndjson(buf) {
+        Ok(buf
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<StdResult<_, _>>()?)
+    } else {
+        Ok(serde_yaml::Deserializer::from_str(buf)
+            .map(T::deserialize)
+            .collect::<StdResult<_, _>>()?)
+    }
+}
+
+/// Whether `buf` is NDJSON rather than YAML, going by every non-blank line
+/// being a JSON object
+pub fn is_ndjson(buf: &str) -> bool {
+    buf.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .all(|l| l.starts_with('{'))
+}
+
+/// Writes `value` to `path` as YAML, replacing the file all at once so an
+/// interrupted write never leaves half a file behind
+pub fn save_yaml<T: Serialize, P: AsRef<Path>>(path: P, value: &T) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    fs::write(&tmp, format!("---\n{}", serde_yaml::to_string(value)?))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// udev properties that differ for every event, and so are never kept
+const VOLATILE_PROPERTIES: &[&str] = &["ACTION", "SEQNUM", "USEC_INITIALIZED"];
+
+/// Whether every key found in both maps has the same value
+fn maps_agree(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> bool {
+    a.iter()
+        .all(|(k, v)| b.get(k).map_or(true, |other| other == v))
+}
+
+/// The value of a sysfs attribute, if it's a short single line of text (i.e.
+/// not a binary blob such as `descriptors`)
+fn attribute_text(value: &OsStr) -> Option<&str> {
+    let value = value.to_str()?.trim_end();
+    (value.len() <= 256 && !value.contains('\n') && !value.contains('\0')).then_some(value)
+}
+
+/// A YAML scalar as a string, since udev values are always strings
+pub fn yaml_scalar(yaml: &Yaml) -> Option<String> {
+    match yaml {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(n) => Some(n.to_string()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// A YAML mapping of strings, i.e. a `properties:` key
+pub fn yaml_map(yaml: &Yaml) -> anyhow::Result<BTreeMap<String, String>> {
+    let Some(hash) = yaml.as_hash() else {
+        bail!("expected a mapping of KEY: VALUE");
+    };
+    hash.iter()
+        .map(|(k, v)| match (yaml_scalar(k), yaml_scalar(v)) {
+            (Some(k), Some(v)) => Ok((k, v)),
+            _ => bail!("expected a mapping of KEY: VALUE strings"),
+        })
+        .collect()
+}
+
 fn empty_if_none<S>(field: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -58,3 +222,49 @@ where
         serializer.serialize_str("")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inventory_single_doc() {
+        let inv = UsbInventory::from_docs(
+            "---\nports:\n  - name: foo\n    sysname: 2-1\ndevices:\n  - name: bar\n    ID_VENDOR_ID: \"0781\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(inv.ports.as_ref().map(Vec::len), Some(1));
+        assert_eq!(inv.devices().len(), 1);
+    }
+
+    #[test]
+    fn inventory_doc_stream() {
+        let inv = UsbInventory::from_docs(
+            "---\nports:\n- sysname: 2-1\ndevices:\n- ID_VENDOR_ID: \"0781\"\n---\nports:\n- sysname: 2-2\n",
+        )
+        .unwrap();
+
+        assert_eq!(inv.ports.as_ref().map(Vec::len), Some(2));
+        assert_eq!(inv.devices().len(), 1);
+    }
+
+    #[test]
+    fn inventory_ndjson() {
+        let inv = UsbInventory::from_docs(
+            "{\"ports\":[{\"name\":\"\",\"sysname\":\"2-1\"}]}\n\n{\"devices\":[{\"NAME\":\"\",\"ID_VENDOR_ID\":\"0781\"}]}\n",
+        )
+        .unwrap();
+
+        assert_eq!(inv.ports.as_ref().map(Vec::len), Some(1));
+        assert_eq!(inv.devices().len(), 1);
+    }
+
+    #[test]
+    fn inventory_empty() {
+        assert_eq!(
+            UsbInventory::from_docs("").unwrap(),
+            UsbInventory::default()
+        );
+    }
+}
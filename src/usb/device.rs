@@ -1,8 +1,14 @@
-use std::fmt::{self, Debug};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+};
 
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
 use yaml_rust::Yaml;
 
+use super::{maps_agree, UsbInterface, VOLATILE_PROPERTIES};
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct UsbDevices {
     pub devices: Vec<UsbDevice>,
@@ -11,8 +17,26 @@ pub struct UsbDevices {
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub struct UsbDevice {
-    #[serde(serialize_with = "super::empty_if_none")]
+    #[serde(
+        rename = "name",
+        alias = "NAME",
+        serialize_with = "super::empty_if_none"
+    )]
     pub name: Option<String>,
+    /// Name of the port the device is expected to be plugged into, used when
+    /// comparing against what's attached rather than to match events
+    #[serde(rename = "port", skip_serializing_if = "Option::is_none", default)]
+    pub port: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    busnum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    devnum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    devname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    driver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    id_bus: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     id_model: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
@@ -22,10 +46,14 @@ pub struct UsbDevice {
     #[serde(skip_serializing_if = "Option::is_none", default)]
     id_model_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
+    id_revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     id_serial: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     id_serial_short: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
+    id_usb_driver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     id_vendor: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     id_vendor_enc: Option<String>,
@@ -35,6 +63,50 @@ pub struct UsbDevice {
     id_vendor_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     product: Option<String>,
+    // sysfs attributes keep their sysfs names
+    //
+    // `speed` and `removable` depend on the port the device is plugged into,
+    // so they aren't compared
+    #[serde(rename = "speed", skip_serializing_if = "Option::is_none", default)]
+    speed: Option<String>,
+    #[serde(
+        rename = "bDeviceClass",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    b_device_class: Option<String>,
+    #[serde(rename = "bcdDevice", skip_serializing_if = "Option::is_none", default)]
+    bcd_device: Option<String>,
+    #[serde(rename = "bMaxPower", skip_serializing_if = "Option::is_none", default)]
+    b_max_power: Option<String>,
+    #[serde(
+        rename = "manufacturer",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    attr_manufacturer: Option<String>,
+    #[serde(rename = "product", skip_serializing_if = "Option::is_none", default)]
+    attr_product: Option<String>,
+    #[serde(rename = "serial", skip_serializing_if = "Option::is_none", default)]
+    attr_serial: Option<String>,
+    #[serde(rename = "removable", skip_serializing_if = "Option::is_none", default)]
+    removable: Option<String>,
+    #[serde(rename = "interfaces", skip_serializing_if = "Vec::is_empty", default)]
+    interfaces: Vec<UsbInterface>,
+    /// Any other udev properties
+    #[serde(
+        rename = "properties",
+        skip_serializing_if = "BTreeMap::is_empty",
+        default
+    )]
+    properties: BTreeMap<String, String>,
+    /// Any other sysfs attributes
+    #[serde(
+        rename = "attributes",
+        skip_serializing_if = "BTreeMap::is_empty",
+        default
+    )]
+    attributes: BTreeMap<String, String>,
 }
 
 impl UsbDevice {
@@ -46,10 +118,17 @@ impl UsbDevice {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.id_model.is_none()
+        self.busnum.is_none()
+            && self.devnum.is_none()
+            && self.devname.is_none()
+            && self.driver.is_none()
+            && self.id_bus.is_none()
+            && self.id_model.is_none()
             && self.id_model_enc.is_none()
             && self.id_model_from_database.is_none()
             && self.id_model_id.is_none()
+            && self.id_revision.is_none()
+            && self.id_usb_driver.is_none()
             && self.id_vendor.is_none()
             && self.id_vendor_enc.is_none()
             && self.id_vendor_from_database.is_none()
@@ -57,6 +136,157 @@ impl UsbDevice {
             && self.id_serial.is_none()
             && self.id_serial_short.is_none()
             && self.product.is_none()
+            && self.speed.is_none()
+            && self.b_device_class.is_none()
+            && self.bcd_device.is_none()
+            && self.b_max_power.is_none()
+            && self.attr_manufacturer.is_none()
+            && self.attr_product.is_none()
+            && self.attr_serial.is_none()
+            && self.removable.is_none()
+            && self.interfaces.is_empty()
+            && self.properties.is_empty()
+            && self.attributes.is_empty()
+    }
+
+    /// Sets the udev property `key` (i.e. `ID_VENDOR_ID`), returning `false`
+    /// if devices don't have such a property
+    pub fn set_property<S: Into<String>>(&mut self, key: &str, value: S) -> bool {
+        let field = match key {
+            "BUSNUM" => &mut self.busnum,
+            "DEVNUM" => &mut self.devnum,
+            "DEVNAME" => &mut self.devname,
+            "DRIVER" => &mut self.driver,
+            "ID_BUS" => &mut self.id_bus,
+            "ID_REVISION" => &mut self.id_revision,
+            "ID_USB_DRIVER" => &mut self.id_usb_driver,
+            "ID_MODEL" => &mut self.id_model,
+            "ID_MODEL_ENC" => &mut self.id_model_enc,
+            "ID_MODEL_FROM_DATABASE" => &mut self.id_model_from_database,
+            "ID_MODEL_ID" => &mut self.id_model_id,
+            "ID_SERIAL" => &mut self.id_serial,
+            "ID_SERIAL_SHORT" => &mut self.id_serial_short,
+            "ID_VENDOR" => &mut self.id_vendor,
+            "ID_VENDOR_ENC" => &mut self.id_vendor_enc,
+            "ID_VENDOR_FROM_DATABASE" => &mut self.id_vendor_from_database,
+            "ID_VENDOR_ID" => &mut self.id_vendor_id,
+            "PRODUCT" => &mut self.product,
+            _ => return false,
+        };
+        *field = Some(value.into());
+        true
+    }
+
+    /// Sets the sysfs attribute `key` (i.e. `bcdDevice`), returning `false`
+    /// if devices don't have such an attribute
+    pub fn set_attribute<S: Into<String>>(&mut self, key: &str, value: S) -> bool {
+        let field = match key {
+            "speed" => &mut self.speed,
+            "bDeviceClass" => &mut self.b_device_class,
+            "bcdDevice" => &mut self.bcd_device,
+            "bMaxPower" => &mut self.b_max_power,
+            "manufacturer" => &mut self.attr_manufacturer,
+            "product" => &mut self.attr_product,
+            "serial" => &mut self.attr_serial,
+            "removable" => &mut self.removable,
+            _ => return false,
+        };
+        *field = Some(value.into());
+        true
+    }
+
+    /// Clears the values which change each time a device is plugged in, so
+    /// what remains can be saved and matched against later
+    ///
+    /// The catch-all `properties` and `attributes` are cleared as well, since
+    /// they're mostly noise; keys worth matching on can be added back by hand.
+    pub fn strip_volatile(&mut self) {
+        self.busnum = None;
+        self.devnum = None;
+        self.devname = None;
+        self.properties.clear();
+        self.attributes.clear();
+    }
+
+    /// Every known udev property and sysfs attribute as `(key, value)`, using
+    /// the same keys as YAML
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let Ok(serde_json::Value::Object(map)) = serde_json::to_value(self) else {
+            return Vec::new();
+        };
+
+        let mut fields = Vec::new();
+        for (key, value) in map {
+            match (key.as_str(), value) {
+                ("name" | "port" | "interfaces" | "properties" | "attributes", _) => (),
+                (_, serde_json::Value::String(v)) => fields.push((key, v)),
+                _ => (),
+            }
+        }
+        fields.extend(self.properties.clone());
+        fields.extend(self.attributes.clone());
+        fields
+    }
+
+    /// A copy named `name` with only the fields in `keys`, see
+    /// [`UsbDevice::fields`]
+    pub fn keep<S: Into<String>>(&self, name: S, keys: &[&str]) -> Self {
+        let mut device = Self::new(name);
+        for (key, value) in self.fields() {
+            if !keys.contains(&key.as_str()) {
+                continue;
+            }
+            if self.properties.contains_key(&key) {
+                device.properties.insert(key, value);
+            } else if self.attributes.contains_key(&key) {
+                device.attributes.insert(key, value);
+            } else if !device.set_property(&key, value.clone()) {
+                device.set_attribute(&key, value);
+            }
+        }
+        device
+    }
+
+    pub fn interfaces(&self) -> &[UsbInterface] { &self.interfaces }
+
+    pub fn with_interfaces(mut self, interfaces: Vec<UsbInterface>) -> Self {
+        self.interfaces = interfaces;
+        self
+    }
+
+    pub fn vendor_id(&self) -> Option<&str> { self.id_vendor_id.as_deref() }
+
+    pub fn model_id(&self) -> Option<&str> { self.id_model_id.as_deref() }
+
+    /// Whether the device is a hub, by its USB class and that of every one
+    /// of its interfaces
+    ///
+    /// The device class is only what the device claims to be, so a "hub" with
+    /// i.e. a keyboard or storage interface isn't one.
+    pub fn is_hub(&self) -> bool {
+        self.b_device_class.as_deref() == Some("09")
+            && !self.interfaces.is_empty()
+            && self.interfaces.iter().all(|i| i.class == 0x09)
+    }
+
+    /// The most human friendly vendor name known
+    pub fn vendor(&self) -> Option<&str> {
+        self.id_vendor_from_database
+            .as_deref()
+            .or(self.id_vendor.as_deref())
+    }
+
+    /// The most human friendly model name known
+    pub fn model(&self) -> Option<&str> {
+        self.id_model_from_database
+            .as_deref()
+            .or(self.id_model.as_deref())
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.id_serial_short
+            .as_deref()
+            .or(self.id_serial.as_deref())
     }
 }
 
@@ -76,68 +306,74 @@ impl fmt::Display for UsbDevice {
     }
 }
 
-impl<'a> From<&'a tokio_udev::Device> for UsbDevice {
+impl From<&tokio_udev::Device> for UsbDevice {
     fn from(d: &tokio_udev::Device) -> Self {
-        Self {
-            id_model: d
-                .property_value("ID_MODEL")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_model_enc: d
-                .property_value("ID_MODEL_ENC")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_model_from_database: d
-                .property_value("ID_MODEL_FROM_DATABASE")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_model_id: d
-                .property_value("ID_MODEL_ID")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_serial: d
-                .property_value("ID_SERIAL")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_serial_short: d
-                .property_value("ID_SERIAL_SHORT")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_vendor: d
-                .property_value("ID_VENDOR")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_vendor_enc: d
-                .property_value("ID_VENDOR_ENC")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_vendor_from_database: d
-                .property_value("ID_VENDOR_FROM_DATABASE")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_vendor_id: d
-                .property_value("ID_VENDOR_ID")
-                .map(|v| v.to_string_lossy().to_string()),
-            product: d
-                .property_value("PRODUCT")
-                .map(|v| v.to_string_lossy().to_string()),
+        let mut device = Self {
+            interfaces: UsbInterface::of_device(d),
             ..Default::default()
+        };
+
+        for prop in d.properties() {
+            let key = prop.name().to_string_lossy();
+            let value = prop.value().to_string_lossy();
+            if !device.set_property(&key, value.clone()) && !VOLATILE_PROPERTIES.contains(&&*key) {
+                device.properties.insert(key.into(), value.into());
+            }
+        }
+        for attr in d.attributes() {
+            let key = attr.name().to_string_lossy();
+            let Some(value) = super::attribute_text(attr.value()) else {
+                continue;
+            };
+            if !device.set_attribute(&key, value) {
+                device.attributes.insert(key.into(), value.into());
+            }
         }
+
+        device
     }
 }
 
-impl<'a> From<&'a Yaml> for UsbDevice {
-    fn from(yaml: &'a Yaml) -> Self {
-        let mut device = if let Some(name) = yaml["name"].as_str() {
-            UsbDevice::new(name)
-        } else {
-            cli_bail!("faild to parse YAML for Device; missing required 'name' key");
+impl<'a> TryFrom<&'a Yaml> for UsbDevice {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let Some(name) = yaml["name"].as_str() else {
+            bail!("device is missing required 'name' key");
         };
+        let mut device = UsbDevice::new(name);
 
-        yaml_str!(device, yaml, id_model);
-        yaml_str!(device, yaml, id_model_enc);
-        yaml_str!(device, yaml, id_model_from_database);
-        yaml_str!(device, yaml, id_model_id);
-        yaml_str!(device, yaml, id_vendor);
-        yaml_str!(device, yaml, id_vendor_enc);
-        yaml_str!(device, yaml, id_vendor_from_database);
-        yaml_str!(device, yaml, id_vendor_id);
-        yaml_str!(device, yaml, id_serial);
-        yaml_str!(device, yaml, id_serial_short);
-        yaml_str!(device, yaml, product);
+        let Some(hash) = yaml.as_hash() else {
+            return Ok(device);
+        };
+        for (key, value) in hash {
+            let Some(key) = key.as_str() else { continue };
+            match key {
+                "name" => (),
+                "port" => device.port = super::yaml_scalar(value),
+                "interfaces" => {
+                    if let Some(ifaces) = value.as_vec() {
+                        device.interfaces = ifaces
+                            .iter()
+                            .map(UsbInterface::try_from)
+                            .collect::<anyhow::Result<_>>()?;
+                    }
+                }
+                "properties" => device.properties.extend(super::yaml_map(value)?),
+                "attributes" => device.attributes.extend(super::yaml_map(value)?),
+                _ => {
+                    let Some(value) = super::yaml_scalar(value) else {
+                        bail!("device '{name}'; '{key}' must be a string");
+                    };
+                    // Older files used the field names, i.e. `id_model`
+                    let _ = device.set_property(key, value.clone())
+                        || device.set_attribute(key, value.clone())
+                        || device.set_property(&key.to_ascii_uppercase(), value);
+                }
+            }
+        }
 
-        device
+        Ok(device)
     }
 }
 
@@ -153,6 +389,13 @@ impl PartialEq<UsbDevice> for UsbDevice {
         };
 
         // We don't compare name because it's always none from one side or the other
+        cmp_ignore_none!(self, other, busnum);
+        cmp_ignore_none!(self, other, devnum);
+        cmp_ignore_none!(self, other, devname);
+        cmp_ignore_none!(self, other, driver);
+        cmp_ignore_none!(self, other, id_bus);
+        cmp_ignore_none!(self, other, id_revision);
+        cmp_ignore_none!(self, other, id_usb_driver);
         cmp_ignore_none!(self, other, id_model);
         cmp_ignore_none!(self, other, id_model_enc);
         cmp_ignore_none!(self, other, id_model_from_database);
@@ -164,8 +407,23 @@ impl PartialEq<UsbDevice> for UsbDevice {
         cmp_ignore_none!(self, other, id_serial);
         cmp_ignore_none!(self, other, id_serial_short);
         cmp_ignore_none!(self, other, product);
+        cmp_ignore_none!(self, other, b_device_class);
+        cmp_ignore_none!(self, other, bcd_device);
+        cmp_ignore_none!(self, other, b_max_power);
+        cmp_ignore_none!(self, other, attr_manufacturer);
+        cmp_ignore_none!(self, other, attr_product);
+        cmp_ignore_none!(self, other, attr_serial);
+
+        if !maps_agree(&self.properties, &other.properties)
+            || !maps_agree(&self.attributes, &other.attributes)
+        {
+            return false;
+        }
 
-        true
+        // Interfaces are only known for devices that have been seen plugged in
+        self.interfaces.is_empty()
+            || other.interfaces.is_empty()
+            || UsbInterface::same_kinds(&self.interfaces, &other.interfaces)
     }
 }
 
@@ -189,6 +447,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -215,6 +474,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -247,6 +507,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -275,6 +536,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -307,6 +569,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -335,6 +598,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -358,6 +622,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         let d2 = UsbDevice::default();
@@ -396,6 +661,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         let d2 = UsbDevice {
@@ -411,6 +677,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         assert_eq!(d1, d2);
@@ -431,6 +698,7 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         let d2 = UsbDevice {
@@ -446,8 +714,141 @@ mod tests {
             id_vendor_from_database: Some("fooz".into()),
             id_vendor_id: Some("fooq".into()),
             product: Some("foom".into()),
+            ..Default::default()
         };
 
         assert_eq!(d2, d1);
     }
+
+    #[test]
+    fn device_properties_and_attributes() {
+        let rule = UsbDevice {
+            name: Some("foo".into()),
+            bcd_device: Some("0100".into()),
+            properties: [("ID_FOO".into(), "bar".into())].into(),
+            ..Default::default()
+        };
+        let mut seen = UsbDevice {
+            bcd_device: Some("0100".into()),
+            properties: [
+                ("ID_FOO".into(), "bar".into()),
+                ("ID_OTHER".into(), "baz".into()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        assert_eq!(rule, seen);
+        seen.properties.insert("ID_FOO".into(), "qux".into());
+        assert!(rule != seen);
+    }
+
+    #[test]
+    fn device_from_yaml() {
+        let yaml = yaml_rust::YamlLoader::load_from_str(
+            "name: foo\nport: Left\nID_VENDOR_ID: '0781'\nBUSNUM: '003'\nbcdDevice: '0100'\nid_model: bar\nproperties:\n  ID_FOO: bar\n",
+        )
+        .unwrap();
+        let mut d = UsbDevice::try_from(&yaml[0]).unwrap();
+
+        assert_eq!(d.port.as_deref(), Some("Left"));
+        assert_eq!(d.id_vendor_id.as_deref(), Some("0781"));
+        assert_eq!(d.busnum.as_deref(), Some("003"));
+        assert_eq!(d.bcd_device.as_deref(), Some("0100"));
+        assert_eq!(d.id_model.as_deref(), Some("bar"));
+        assert_eq!(d.properties.get("ID_FOO").map(String::as_str), Some("bar"));
+
+        d.strip_volatile();
+        assert!(d.busnum.is_none() && d.properties.is_empty());
+        assert_eq!(d.bcd_device.as_deref(), Some("0100"));
+    }
+
+    #[test]
+    fn device_serialize_names() {
+        let d = UsbDevice {
+            name: Some("foo".into()),
+            b_max_power: Some("100mA".into()),
+            attr_product: Some("Ultra Fit".into()),
+            product: Some("781/5583/100".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_yaml::to_string(&d).unwrap(),
+            "name: foo\nPRODUCT: 781/5583/100\nbMaxPower: 100mA\nproduct: Ultra Fit\n"
+        );
+    }
+
+    #[test]
+    fn device_keep_fields() {
+        let d = UsbDevice {
+            id_vendor_id: Some("1050".into()),
+            id_model_id: Some("0407".into()),
+            id_serial_short: Some("123".into()),
+            bcd_device: Some("0512".into()),
+            properties: [("ID_FOO".into(), "bar".into())].into(),
+            ..Default::default()
+        };
+
+        let keys: Vec<_> = d.fields().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            [
+                "ID_MODEL_ID",
+                "ID_SERIAL_SHORT",
+                "ID_VENDOR_ID",
+                "bcdDevice",
+                "ID_FOO"
+            ]
+        );
+
+        let kept = d.keep("key", &["ID_VENDOR_ID", "bcdDevice", "ID_FOO"]);
+        assert_eq!(kept.name.as_deref(), Some("key"));
+        assert_eq!(kept.id_vendor_id.as_deref(), Some("1050"));
+        assert_eq!(kept.bcd_device.as_deref(), Some("0512"));
+        assert!(kept.id_model_id.is_none() && kept.id_serial_short.is_none());
+        assert_eq!(kept.properties.len(), 1);
+    }
+
+    #[test]
+    fn device_eq_in_other_port() {
+        let mut usb2_front = UsbDevice::new("key");
+        usb2_front.set_property("ID_VENDOR_ID", "1050");
+        usb2_front.set_property("ID_SERIAL_SHORT", "123");
+        let mut usb3_rear = usb2_front.clone();
+        usb2_front.set_property("speed", "480");
+        usb2_front.set_property("removable", "removable");
+        usb3_rear.set_property("speed", "5000");
+        usb3_rear.set_property("removable", "fixed");
+
+        assert_eq!(usb2_front, usb3_rear);
+        assert_eq!(usb3_rear, usb2_front);
+        usb3_rear.set_property("ID_SERIAL_SHORT", "456");
+        assert_ne!(usb2_front, usb3_rear);
+    }
+
+    #[test]
+    fn device_eq_interfaces_from_nodes_or_property() {
+        // As read from the child nodes when scanned
+        let numbered = |number, class, protocol| UsbInterface {
+            number: Some(number),
+            class,
+            subclass: 0x01,
+            protocol,
+        };
+        let scanned = UsbDevice::new("kbd").with_interfaces(vec![
+            numbered(0, 0x03, 0x01),
+            numbered(1, 0x03, 0x02),
+            numbered(2, 0x03, 0x01),
+        ]);
+        // As read from ID_USB_INTERFACES before the children exist
+        let added = UsbDevice::new("kbd")
+            .with_interfaces(UsbInterface::parse_id_usb_interfaces(":030101:030102:"));
+        let storage = UsbDevice::new("kbd")
+            .with_interfaces(UsbInterface::parse_id_usb_interfaces(":080650:"));
+
+        assert_eq!(scanned, added);
+        assert_eq!(added, scanned);
+        assert_ne!(scanned, storage);
+    }
 }
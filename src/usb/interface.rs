@@ -0,0 +1,235 @@
+use std::{collections::BTreeSet, fmt};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio_udev::Enumerator;
+use yaml_rust::Yaml;
+
+/// One interface of a USB device, i.e. a HID keyboard is class `03`, subclass
+/// `01`, protocol `01`
+///
+/// Codes are displayed and written in hexadecimal, the same as `lsusb` and
+/// udev's `ID_USB_INTERFACES`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsbInterface {
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "hex_opt")]
+    pub number: Option<u8>,
+    #[serde(with = "hex")]
+    pub class: u8,
+    #[serde(with = "hex")]
+    pub subclass: u8,
+    #[serde(with = "hex")]
+    pub protocol: u8,
+}
+
+impl fmt::Display for UsbInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}",
+            self.class, self.subclass, self.protocol
+        )
+    }
+}
+
+impl UsbInterface {
+    /// Whether `a` and `b` have the same kinds of interfaces
+    ///
+    /// Interfaces read from child nodes are numbered while those from
+    /// `ID_USB_INTERFACES` are not and have duplicates removed, so only the
+    /// set of class, subclass and protocol codes is compared.
+    pub fn same_kinds(a: &[Self], b: &[Self]) -> bool {
+        let kinds = |ifaces: &[Self]| -> BTreeSet<(u8, u8, u8)> {
+            ifaces
+                .iter()
+                .map(|i| (i.class, i.subclass, i.protocol))
+                .collect()
+        };
+        kinds(a) == kinds(b)
+    }
+
+    /// Parses udev's `ID_USB_INTERFACES` property, i.e. `:030101:030102:`
+    pub fn parse_id_usb_interfaces(s: &str) -> Vec<Self> {
+        s.split(':')
+            .filter_map(|triple| {
+                if triple.len() != 6 || !triple.is_ascii() {
+                    return None;
+                }
+                Some(Self {
+                    number: None,
+                    class: u8::from_str_radix(&triple[0..2], 16).ok()?,
+                    subclass: u8::from_str_radix(&triple[2..4], 16).ok()?,
+                    protocol: u8::from_str_radix(&triple[4..6], 16).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Reads the interfaces of a `usb_device` from its child `usb_interface`
+    /// nodes, or from `ID_USB_INTERFACES` when the children don't exist (yet)
+    pub fn of_device(d: &tokio_udev::Device) -> Vec<Self> {
+        let mut ifaces = Self::children(d).unwrap_or_default();
+        if ifaces.is_empty() {
+            if let Some(v) = d.property_value("ID_USB_INTERFACES") {
+                ifaces = Self::parse_id_usb_interfaces(&v.to_string_lossy());
+            }
+        }
+        ifaces
+    }
+
+    fn children(d: &tokio_udev::Device) -> std::io::Result<Vec<Self>> {
+        let attr = |d: &tokio_udev::Device, name: &str| {
+            d.attribute_value(name)
+                .and_then(|v| u8::from_str_radix(v.to_str()?.trim(), 16).ok())
+        };
+
+        let mut scanner = Enumerator::new()?;
+        scanner.match_parent(d)?;
+        scanner.match_subsystem("usb")?;
+        scanner.match_property("DEVTYPE", "usb_interface")?;
+
+        let mut ifaces: Vec<Self> = scanner
+            .scan_devices()?
+            .filter_map(|child| {
+                Some(Self {
+                    number: attr(&child, "bInterfaceNumber"),
+                    class: attr(&child, "bInterfaceClass")?,
+                    subclass: attr(&child, "bInterfaceSubClass")?,
+                    protocol: attr(&child, "bInterfaceProtocol")?,
+                })
+            })
+            .collect();
+        ifaces.sort_by_key(|i| i.number);
+        Ok(ifaces)
+    }
+}
+
+impl<'a> TryFrom<&'a Yaml> for UsbInterface {
+    type Error = anyhow::Error;
+
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let code = |key: &str| yaml_code(&yaml[key]);
+        let (Some(class), Some(subclass), Some(protocol)) =
+            (code("class"), code("subclass"), code("protocol"))
+        else {
+            bail!("interface requires 'class', 'subclass' and 'protocol' keys as hex codes");
+        };
+
+        Ok(Self {
+            number: code("number"),
+            class,
+            subclass,
+            protocol,
+        })
+    }
+}
+
+/// Reads a class, subclass or protocol code from YAML, see [`parse_code`]
+pub fn yaml_code(yaml: &Yaml) -> Option<u8> {
+    match yaml {
+        Yaml::Integer(n) => parse_code(&n.to_string()),
+        Yaml::String(s) | Yaml::Real(s) => parse_code(s),
+        _ => None,
+    }
+}
+
+/// Parses a class, subclass or protocol code as hexadecimal
+///
+/// YAML reads an unquoted `03` or `10` as an integer, so integers are
+/// re-read as if their digits were hex to match how the codes are written
+/// everywhere else. A `0x` prefix is accepted, but must be quoted.
+pub fn parse_code(s: &str) -> Option<u8> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u8::from_str_radix(s, 16).ok()
+}
+
+mod hex {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &u8, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{v:02x}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u8, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Code {
+            Int(u64),
+            Str(String),
+        }
+
+        let s = match Code::deserialize(d)? {
+            Code::Int(n) => n.to_string(),
+            Code::Str(s) => s,
+        };
+        super::parse_code(&s).ok_or_else(|| de::Error::custom(format!("invalid hex code '{s}'")))
+    }
+}
+
+mod hex_opt {
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(v: &Option<u8>, s: S) -> Result<S::Ok, S::Error> {
+        match v {
+            Some(v) => super::hex::serialize(v, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u8>, D::Error> {
+        super::hex::deserialize(d).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_id_usb_interfaces() {
+        let ifaces = UsbInterface::parse_id_usb_interfaces(":030101:030102:ff0000:");
+
+        assert_eq!(ifaces.len(), 3);
+        assert_eq!(
+            ifaces[0],
+            UsbInterface {
+                number: None,
+                class: 0x03,
+                subclass: 0x01,
+                protocol: 0x01,
+            }
+        );
+        assert_eq!(ifaces[2].class, 0xff);
+    }
+
+    #[test]
+    fn parse_id_usb_interfaces_garbage() {
+        assert!(UsbInterface::parse_id_usb_interfaces("").is_empty());
+        assert!(UsbInterface::parse_id_usb_interfaces(":0301:zz0101:").is_empty());
+    }
+
+    #[test]
+    fn parse_codes() {
+        assert_eq!(parse_code("03"), Some(0x03));
+        assert_eq!(parse_code("10"), Some(0x10));
+        assert_eq!(parse_code("0xe0"), Some(0xe0));
+        assert_eq!(parse_code("FF"), Some(0xff));
+        assert_eq!(parse_code("100"), None);
+    }
+
+    #[test]
+    fn interface_yaml_round_trip() {
+        let iface: UsbInterface =
+            serde_yaml::from_str("class: 03\nsubclass: \"01\"\nprotocol: \"0x02\"\n").unwrap();
+
+        assert_eq!((iface.class, iface.subclass, iface.protocol), (3, 1, 2));
+        assert_eq!(
+            serde_yaml::to_string(&iface).unwrap(),
+            "class: '03'\nsubclass: '01'\nprotocol: '02'\n"
+        );
+    }
+}
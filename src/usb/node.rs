@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tokio_udev::Enumerator;
+
+use super::VOLATILE_PROPERTIES;
+
+/// Subsystems whose device nodes are linked to the USB device they belong to
+pub const NODE_SUBSYSTEMS: &[&str] = &["block", "tty", "hidraw", "net"];
+
+/// Devices in other subsystems created for a USB device, keyed by subsystem
+///
+/// i.e. `block` holds the disk and partitions of a USB stick, `tty` the
+/// `/dev/ttyUSB0` of a serial adapter and `net` the interface of a NIC.
+pub type DeviceNodes = BTreeMap<String, Vec<DeviceNode>>;
+
+/// A child device of a USB device, such as a partition of a USB stick
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceNode {
+    pub sysname: String,
+    /// All udev properties, i.e. `DEVNAME` or `ID_FS_LABEL`
+    #[serde(flatten)]
+    pub properties: BTreeMap<String, String>,
+}
+
+impl DeviceNode {
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+
+    /// Finds the devices in `subsystems` that live under `syspath`
+    pub fn children_of(syspath: &Path, subsystems: &[&str]) -> std::io::Result<DeviceNodes> {
+        let parent = tokio_udev::Device::from_syspath(syspath)?;
+
+        let mut nodes = DeviceNodes::new();
+        for subsystem in subsystems {
+            let mut scanner = Enumerator::new()?;
+            scanner.match_parent(&parent)?;
+            scanner.match_subsystem(subsystem)?;
+
+            let mut found: Vec<_> = scanner.scan_devices()?.map(|d| Self::from(&d)).collect();
+            if found.is_empty() {
+                continue;
+            }
+            found.sort_by(|a, b| a.sysname.cmp(&b.sysname));
+            nodes.insert(subsystem.to_string(), found);
+        }
+        Ok(nodes)
+    }
+}
+
+impl From<&tokio_udev::Device> for DeviceNode {
+    fn from(d: &tokio_udev::Device) -> Self {
+        Self {
+            sysname: d.sysname().to_string_lossy().into(),
+            properties: d
+                .properties()
+                .filter(|p| !VOLATILE_PROPERTIES.contains(&&*p.name().to_string_lossy()))
+                .map(|p| {
+                    (
+                        p.name().to_string_lossy().into(),
+                        p.value().to_string_lossy().into(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use std::fmt::{self, Debug};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+};
 
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use yaml_rust::Yaml;
 
+use super::{maps_agree, topology, ChainPattern, Topology, VOLATILE_PROPERTIES};
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct UsbPorts {
     pub ports: Vec<UsbPort>,
@@ -34,6 +40,22 @@ pub struct UsbPort {
         default
     )]
     id_path_tag: Option<String>,
+    /// Host controller the port must be on, i.e. `pci-0000:00:14.0`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    controller: Option<String>,
+    /// Port chains the port must match any of, see [`ChainPattern`]
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        deserialize_with = "topology::one_or_many"
+    )]
+    chain: Vec<ChainPattern>,
+    /// Any other udev properties
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    properties: BTreeMap<String, String>,
+    /// Any other sysfs attributes
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    attributes: BTreeMap<String, String>,
 }
 
 impl UsbPort {
@@ -52,7 +74,88 @@ impl UsbPort {
             && self.id_for_seat.is_none()
             && self.id_path.is_none()
             && self.id_path_tag.is_none()
+            && self.controller.is_none()
+            && self.chain.is_empty()
+            && self.properties.is_empty()
+            && self.attributes.is_empty()
+    }
+
+    /// Sets the property `key` (i.e. `sysname` or `ID_PATH`), returning
+    /// `false` if ports don't have such a property or `value` isn't valid for
+    /// it
+    pub fn set_property<S: Into<String>>(&mut self, key: &str, value: S) -> bool {
+        self.try_set_property(key, value).unwrap_or(false)
+    }
+
+    /// Like [`UsbPort::set_property`], but failing when `value` isn't valid
+    /// for `key` (i.e. a `sysnum` that isn't a number)
+    pub fn try_set_property<S: Into<String>>(
+        &mut self,
+        key: &str,
+        value: S,
+    ) -> anyhow::Result<bool> {
+        let field = match key {
+            "syspath" => &mut self.syspath,
+            "devpath" => &mut self.devpath,
+            "sysname" => &mut self.sysname,
+            "ID_FOR_SEAT" => &mut self.id_for_seat,
+            "ID_PATH" => &mut self.id_path,
+            "ID_PATH_TAG" => &mut self.id_path_tag,
+            "controller" => &mut self.controller,
+            "chain" => {
+                let chain = value.into().parse().context("invalid value for 'chain'")?;
+                self.chain.push(chain);
+                return Ok(true);
+            }
+            "sysnum" => {
+                let sysnum = value.into().parse().context("invalid value for 'sysnum'")?;
+                self.sysnum = Some(sysnum);
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        *field = Some(value.into());
+        Ok(true)
+    }
+
+    /// Clears the catch-all `properties` and `attributes`, see
+    /// [`UsbDevice::strip_volatile`](super::UsbDevice::strip_volatile)
+    pub fn strip_volatile(&mut self) {
+        self.properties.clear();
+        self.attributes.clear();
+    }
+
+    /// Where the port is in the USB tree, if it's a port that was seen rather
+    /// than only defined
+    pub fn topology(&self) -> Option<Topology> {
+        Topology::parse(self.sysname.as_deref(), self.id_path.as_deref())
+    }
+
+    /// Whether `other`'s location satisfies this port's `controller` and
+    /// `chain`
+    fn located_like(&self, other: &UsbPort) -> bool {
+        let Some(t) = other.topology() else {
+            return true;
+        };
+        let chain_ok = self.chain.is_empty() || self.chain.iter().any(|p| p.matches(&t.ports));
+        let controller_ok = match (&self.controller, &t.controller) {
+            (Some(want), Some(got)) => want == got,
+            _ => true,
+        };
+        chain_ok && controller_ok
     }
+
+    pub fn syspath(&self) -> Option<&str> { self.syspath.as_deref() }
+
+    pub fn sysname(&self) -> Option<&str> { self.sysname.as_deref() }
+
+    /// Root hubs (i.e. `usb2`) belong to the host controller rather than
+    /// being plugged in
+    pub fn is_root_hub(&self) -> bool { self.sysname().is_some_and(|s| s.starts_with("usb")) }
+
+    pub fn devpath(&self) -> Option<&str> { self.devpath.as_deref() }
+
+    pub fn id_path(&self) -> Option<&str> { self.id_path.as_deref() }
 }
 
 impl fmt::Display for UsbPort {
@@ -71,47 +174,82 @@ impl fmt::Display for UsbPort {
     }
 }
 
-impl<'a> From<&'a tokio_udev::Device> for UsbPort {
+impl From<&tokio_udev::Device> for UsbPort {
     fn from(d: &tokio_udev::Device) -> Self {
-        Self {
+        let mut port = Self {
             syspath: Some(d.syspath().to_string_lossy().to_string()),
             devpath: Some(d.devpath().to_string_lossy().to_string()),
             sysname: Some(d.sysname().to_string_lossy().to_string()),
             sysnum: d.sysnum(),
-            id_for_seat: d
-                .property_value("ID_FOR_SEAT")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_path: d
-                .property_value("ID_PATH")
-                .map(|v| v.to_string_lossy().to_string()),
-            id_path_tag: d
-                .property_value("ID_PATH_TAG")
-                .map(|v| v.to_string_lossy().to_string()),
             ..Default::default()
+        };
+
+        for prop in d.properties() {
+            let key = prop.name().to_string_lossy();
+            let value = prop.value().to_string_lossy();
+            // DEVPATH is the same as `devpath`
+            if key != "DEVPATH"
+                && !port.set_property(&key, value.clone())
+                && !VOLATILE_PROPERTIES.contains(&&*key)
+            {
+                port.properties.insert(key.into(), value.into());
+            }
         }
+        for attr in d.attributes() {
+            if let Some(value) = super::attribute_text(attr.value()) {
+                port.attributes
+                    .insert(attr.name().to_string_lossy().into(), value.into());
+            }
+        }
+
+        port
     }
 }
 
-impl<'a> From<&'a Yaml> for UsbPort {
-    fn from(yaml: &'a Yaml) -> Self {
-        let mut port = if let Some(name) = yaml["name"].as_str() {
-            UsbPort::new(name)
-        } else {
-            cli_bail!("failed to parse YAML for port; missing required 'name' key");
-        };
+impl<'a> TryFrom<&'a Yaml> for UsbPort {
+    type Error = anyhow::Error;
 
-        yaml_str!(port, yaml, syspath);
-        yaml_str!(port, yaml, devpath);
-        yaml_str!(port, yaml, sysname);
-        yaml_str!(port, yaml, id_for_seat);
-        yaml_str!(port, yaml, id_path);
-        yaml_str!(port, yaml, id_path_tag);
+    fn try_from(yaml: &'a Yaml) -> anyhow::Result<Self> {
+        let Some(name) = yaml["name"].as_str() else {
+            bail!("port is missing required 'name' key");
+        };
+        let mut port = UsbPort::new(name);
 
-        if let Some(v) = yaml["sysnum"].as_i64() {
-            port.sysnum = Some(v as usize);
+        let Some(hash) = yaml.as_hash() else {
+            return Ok(port);
+        };
+        for (key, value) in hash {
+            let Some(key) = key.as_str() else { continue };
+            match key {
+                "name" => (),
+                "chain" => {
+                    let patterns = match value {
+                        Yaml::Array(list) => list.iter().collect(),
+                        single => vec![single],
+                    };
+                    for p in patterns {
+                        let parsed = super::yaml_scalar(p).map(|s| s.parse::<ChainPattern>());
+                        match parsed {
+                            Some(Ok(chain)) => port.chain.push(chain),
+                            Some(Err(err)) => bail!("port '{name}'; {err}"),
+                            None => bail!("port '{name}'; 'chain' must be a string or list"),
+                        }
+                    }
+                }
+                "properties" => port.properties.extend(super::yaml_map(value)?),
+                "attributes" => port.attributes.extend(super::yaml_map(value)?),
+                _ => {
+                    let Some(value) = super::yaml_scalar(value) else {
+                        bail!("port '{name}'; '{key}' must be a string");
+                    };
+                    // Older files used the field names, i.e. `id_path`
+                    let _ = port.set_property(key, value.clone())
+                        || port.set_property(&key.to_ascii_uppercase(), value);
+                }
+            }
         }
 
-        port
+        Ok(port)
     }
 }
 
@@ -134,8 +272,14 @@ impl PartialEq<UsbPort> for UsbPort {
         cmp_ignore_none!(self, other, id_for_seat);
         cmp_ignore_none!(self, other, id_path);
         cmp_ignore_none!(self, other, id_path_tag);
+        cmp_ignore_none!(self, other, controller);
 
-        true
+        if !self.located_like(other) || !other.located_like(self) {
+            return false;
+        }
+
+        maps_agree(&self.properties, &other.properties)
+            && maps_agree(&self.attributes, &other.attributes)
     }
 }
 
@@ -155,6 +299,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -178,6 +323,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -205,6 +351,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -229,6 +376,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -256,6 +404,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -280,6 +429,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         // Less Specific
@@ -299,6 +449,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         let p2 = UsbPort::default();
@@ -333,6 +484,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         let p2 = UsbPort {
@@ -344,6 +496,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         assert_eq!(p1, p2);
@@ -360,6 +513,7 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         let p2 = UsbPort {
@@ -371,8 +525,60 @@ mod tests {
             id_for_seat: Some("foog".into()),
             id_path: Some("fool".into()),
             id_path_tag: Some("foop".into()),
+            ..Default::default()
         };
 
         assert_eq!(p2, p1);
     }
+
+    #[test]
+    fn port_eq_chain() {
+        let dock = UsbPort {
+            name: Some("dock".into()),
+            chain: vec!["1.4.*.**".parse().unwrap()],
+            ..Default::default()
+        };
+        let seen = |sysname: &str| UsbPort {
+            sysname: Some(sysname.into()),
+            syspath: Some(format!(
+                "/sys/devices/pci0000:00/0000:00:14.0/usb2/{sysname}"
+            )),
+            ..Default::default()
+        };
+
+        assert_eq!(dock, seen("2-1.4.2"));
+        assert_eq!(seen("3-1.4.2.1"), dock);
+        assert!(dock != seen("2-1.4"));
+        assert!(dock != seen("2-2.4.1"));
+    }
+
+    #[test]
+    fn port_eq_controller() {
+        let front = UsbPort {
+            name: Some("front".into()),
+            controller: Some("pci-0000:00:14.0".into()),
+            chain: vec!["3".parse().unwrap(), "4".parse().unwrap()],
+            ..Default::default()
+        };
+        let seen = |controller: &str, chain: &str| UsbPort {
+            sysname: Some(format!("1-{chain}")),
+            id_path: Some(format!("{controller}-usb-0:{chain}")),
+            ..Default::default()
+        };
+
+        assert_eq!(front, seen("pci-0000:00:14.0", "4"));
+        assert!(front != seen("pci-0000:00:14.0", "5"));
+        assert!(front != seen("pci-0000:00:0d.0", "4"));
+    }
+
+    #[test]
+    fn port_set_property_invalid() {
+        let mut port = UsbPort::default();
+
+        assert!(port.try_set_property("sysnum", "3").unwrap());
+        assert!(!port.try_set_property("nope", "3").unwrap());
+        let err = port.try_set_property("sysnum", "abc").unwrap_err();
+        assert_eq!(err.to_string(), "invalid value for 'sysnum'");
+        assert!(!port.set_property("sysnum", "abc"));
+    }
 }
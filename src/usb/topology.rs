@@ -0,0 +1,221 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Where a port sits in the USB tree
+///
+/// Parsed from a sysname such as `2-1.4.3`, which is bus `2`, root port `1`,
+/// then port `4` of the hub plugged into it, then port `3` of the hub plugged
+/// into that. Root hubs (`usb2`) have no ports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Topology {
+    /// Bus numbers are handed out in probe order, so aren't stable across
+    /// boots or docking stations
+    pub bus: Option<u32>,
+    /// The host controller from `ID_PATH`, i.e. `pci-0000:00:14.0`
+    pub controller: Option<String>,
+    /// The root port followed by the port on each hub along the way
+    pub ports: Vec<u32>,
+}
+
+impl Topology {
+    pub fn parse(sysname: Option<&str>, id_path: Option<&str>) -> Option<Self> {
+        let controller = id_path
+            .and_then(|p| p.split_once("-usb-"))
+            .map(|(c, _)| c.to_string());
+
+        if let Some(bus) = sysname.and_then(|s| s.strip_prefix("usb")) {
+            return Some(Self {
+                bus: bus.parse().ok(),
+                controller,
+                ports: Vec::new(),
+            });
+        }
+
+        // Falls back to ID_PATH's `usb-0:1.4.3` when there's no sysname
+        let (bus, chain) = match sysname {
+            Some(s) => {
+                let (bus, chain) = s.split_once('-')?;
+                (bus.parse().ok(), chain)
+            }
+            None => (None, id_path?.rsplit_once("-usb-")?.1.split_once(':')?.1),
+        };
+        // Interfaces have a `:config.interface` suffix
+        let chain = chain.split(':').next()?;
+
+        Some(Self {
+            bus,
+            controller,
+            ports: chain
+                .split('.')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?,
+        })
+    }
+
+    /// How many hubs deep the port is, where root ports are `1`
+    pub fn depth(&self) -> usize { self.ports.len() }
+
+    /// The ports of the hubs between the root port and this one
+    pub fn hubs(&self) -> &[u32] { &self.ports[..self.ports.len().saturating_sub(1)] }
+}
+
+/// A pattern over the port chain of a [`Topology`], i.e. `1.4.*`
+///
+/// Each `.` separated segment is a port number, `*` for any single port, or
+/// `**` for any number of ports (including none). So `1.4.**` is hub `1.4` and
+/// everything behind it, and `**.2` is port `2` of any hub no matter where the
+/// hub is plugged in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainPattern(Vec<Segment>);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Port(u32),
+    Any,
+    AnyDepth,
+}
+
+impl ChainPattern {
+    pub fn matches(&self, ports: &[u32]) -> bool { glob(&self.0, ports) }
+}
+
+fn glob(pattern: &[Segment], ports: &[u32]) -> bool {
+    match pattern.split_first() {
+        None => ports.is_empty(),
+        Some((Segment::AnyDepth, rest)) => (0..=ports.len()).any(|i| glob(rest, &ports[i..])),
+        Some((seg, rest)) => match ports.split_first() {
+            Some((port, ports)) => {
+                (*seg == Segment::Any || *seg == Segment::Port(*port)) && glob(rest, ports)
+            }
+            None => false,
+        },
+    }
+}
+
+impl FromStr for ChainPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s
+            .split('.')
+            .map(|seg| match seg.trim() {
+                "*" => Ok(Segment::Any),
+                "**" => Ok(Segment::AnyDepth),
+                n => match n.parse() {
+                    Ok(n) => Ok(Segment::Port(n)),
+                    Err(_) => {
+                        bail!("invalid port chain '{s}'; '{n}' is not a port number, '*' or '**'")
+                    }
+                },
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(segments))
+    }
+}
+
+impl fmt::Display for ChainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, seg) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            match seg {
+                Segment::Port(n) => write!(f, "{n}")?,
+                Segment::Any => f.write_str("*")?,
+                Segment::AnyDepth => f.write_str("**")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ChainPattern {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> { s.collect_str(self) }
+}
+
+impl<'de> Deserialize<'de> for ChainPattern {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        // An unquoted `1` is read as a number, and `1.4` as a float which
+        // can't be trusted since `1.10` would become `1.1`
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(u64),
+            Float(f64),
+            Str(String),
+        }
+
+        let s = match Raw::deserialize(d)? {
+            Raw::Int(n) => n.to_string(),
+            Raw::Float(n) => {
+                return Err(de::Error::custom(format!(
+                    "port chain {n} must be quoted, i.e. '{n}'"
+                )))
+            }
+            Raw::Str(s) => s,
+        };
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Reads either a single pattern or a list of them
+pub fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<ChainPattern>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ChainPattern),
+        Many(Vec<ChainPattern>),
+    }
+
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(p) => vec![p],
+        OneOrMany::Many(ps) => ps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> ChainPattern { s.parse().unwrap() }
+
+    #[test]
+    fn topology_from_sysname() {
+        let t = Topology::parse(Some("2-1.4.3"), Some("pci-0000:00:14.0-usb-0:1.4.3")).unwrap();
+
+        assert_eq!(t.bus, Some(2));
+        assert_eq!(t.controller.as_deref(), Some("pci-0000:00:14.0"));
+        assert_eq!(t.ports, [1, 4, 3]);
+        assert_eq!(t.depth(), 3);
+        assert_eq!(t.hubs(), [1, 4]);
+    }
+
+    #[test]
+    fn topology_root_hub_and_id_path() {
+        let root = Topology::parse(Some("usb2"), None).unwrap();
+        assert_eq!((root.bus, root.depth()), (Some(2), 0));
+
+        let t = Topology::parse(None, Some("pci-0000:00:14.0-usb-0:3.2")).unwrap();
+        assert_eq!((t.bus, t.ports), (None, vec![3, 2]));
+
+        assert_eq!(Topology::parse(Some("2-1:1.0"), None).unwrap().ports, [1]);
+        assert!(Topology::parse(Some("garbage"), None).is_none());
+    }
+
+    #[test]
+    fn chain_patterns() {
+        assert!(pattern("1.4.3").matches(&[1, 4, 3]));
+        assert!(!pattern("1.4").matches(&[1, 4, 3]));
+        assert!(pattern("1.*.3").matches(&[1, 4, 3]));
+        assert!(pattern("1.4.**").matches(&[1, 4]));
+        assert!(pattern("1.4.**").matches(&[1, 4, 2, 1]));
+        assert!(!pattern("1.4.*.**").matches(&[1, 4]));
+        assert!(pattern("**.2.1").matches(&[7, 3, 2, 1]));
+        assert!(!pattern("**.2.1").matches(&[2, 1, 3]));
+        assert!("1.x".parse::<ChainPattern>().is_err());
+        assert_eq!(pattern("1.*.**").to_string(), "1.*.**");
+    }
+}
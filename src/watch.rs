@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use inotify::{EventStream, Inotify, WatchDescriptor, WatchMask};
+use tokio_stream::StreamExt;
+use tracing::{debug, span, trace, Instrument, Level};
+
+use crate::rule::is_rules_file;
+
+/// How long the files have to be left alone before they count as changed
+///
+/// Editors and configuration management often write a temporary file and
+/// rename it over the original, or write in several steps, so reloading on
+/// the first event would read a half written file.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches files for changes with inotify
+///
+/// The directories holding the files are watched rather than the files
+/// themselves, so a file being replaced (i.e. renamed over) is seen as a
+/// change instead of the watch silently following the old file away.
+pub struct FileWatcher {
+    events: EventStream<Vec<u8>>,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    files: HashSet<PathBuf>,
+    /// Rules directories, where any rules file added, changed or removed
+    /// counts
+    rule_dirs: HashSet<PathBuf>,
+}
+
+impl FileWatcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            events: Inotify::init()?.into_event_stream(vec![0; 4096])?,
+            dirs: HashMap::new(),
+            files: HashSet::new(),
+            rule_dirs: HashSet::new(),
+        })
+    }
+
+    /// Watches exactly `files`, dropping any previously watched ones that
+    /// aren't among them
+    ///
+    /// A directory among `files` is a rules directory, and stands for the
+    /// rules files in it.
+    pub fn watch<P: AsRef<Path>>(&mut self, files: impl IntoIterator<Item = P>) -> io::Result<()> {
+        let cwd = env::current_dir()?;
+        (self.rule_dirs, self.files) = files
+            .into_iter()
+            .map(|f| cwd.join(f))
+            .partition(|f| f.is_dir());
+
+        let wanted: HashSet<&Path> = self
+            .files
+            .iter()
+            .filter_map(|f| f.parent())
+            .chain(self.rule_dirs.iter().map(PathBuf::as_path))
+            .collect();
+        let mut watches = self.events.watches();
+        let stale: Vec<_> = self
+            .dirs
+            .iter()
+            .filter(|(_, dir)| !wanted.contains(dir.as_path()))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in stale {
+            self.dirs.remove(&wd);
+            // Fails if the directory has already gone away, which removes the
+            // watch anyway
+            let _ = watches.remove(wd);
+        }
+
+        for dir in wanted {
+            if self.dirs.values().any(|d| d == dir) {
+                continue;
+            }
+            debug!(dir = ?dir, "Watching directory");
+            let wd = watches.add(
+                dir,
+                WatchMask::CLOSE_WRITE
+                    | WatchMask::MOVED_TO
+                    | WatchMask::MOVED_FROM
+                    | WatchMask::CREATE
+                    | WatchMask::DELETE,
+            )?;
+            self.dirs.insert(wd, dir.into());
+        }
+
+        Ok(())
+    }
+
+    /// Waits until one of the watched files changes, and then until they've
+    /// been quiet for a moment
+    pub async fn changed(&mut self) -> io::Result<()> {
+        // Entering the span would attribute whatever else runs while this
+        // waits to it
+        self.next_change()
+            .instrument(span!(Level::TRACE, "fn changed"))
+            .await
+    }
+
+    async fn next_change(&mut self) -> io::Result<()> {
+        while let Some(event) = self.events.next().await {
+            let event = event?;
+            let Some(dir) = self.dirs.get(&event.wd) else {
+                continue;
+            };
+            let Some(name) = event.name else {
+                continue;
+            };
+            let path = dir.join(name);
+            trace!(path = ?path, mask = ?event.mask, "File event");
+            let in_rule_dir = self.rule_dirs.contains(dir) && is_rules_file(&path);
+            if !self.files.contains(&path) && !in_rule_dir {
+                continue;
+            }
+
+            debug!(path = ?path, "Watched file changed");
+            while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, self.events.next()).await {
+                event?;
+            }
+            return Ok(());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "inotify stream ended",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn watch_changed() {
+        let dir = TempDir::new("watch");
+        let rules = dir.join("rules.yml");
+        let other = dir.join("other.yml");
+        fs::write(&rules, "rules: []\n").unwrap();
+
+        let mut watcher = FileWatcher::new().unwrap();
+        watcher.watch([&rules]).unwrap();
+
+        // Files that aren't watched don't count
+        fs::write(&other, "").unwrap();
+        let quiet = tokio::time::timeout(DEBOUNCE * 2, watcher.changed()).await;
+        assert!(quiet.is_err());
+
+        // Replacing the file like an editor would
+        let tmp = dir.join(".rules.yml.tmp");
+        fs::write(&tmp, "rules: []\n").unwrap();
+        fs::rename(&tmp, &rules).unwrap();
+        let changed = tokio::time::timeout(DEBOUNCE * 4, watcher.changed()).await;
+        assert!(changed.unwrap().is_ok());
+
+        // Rules files dropped into a rules directory count too
+        let rules_d = dir.join("rules.d");
+        fs::create_dir_all(&rules_d).unwrap();
+        watcher.watch([&rules, &rules_d]).unwrap();
+        fs::write(rules_d.join("notes.txt"), "").unwrap();
+        let quiet = tokio::time::timeout(DEBOUNCE * 2, watcher.changed()).await;
+        assert!(quiet.is_err());
+        fs::write(rules_d.join("10-kiosk.yml"), "rules: []\n").unwrap();
+        let changed = tokio::time::timeout(DEBOUNCE * 4, watcher.changed()).await;
+        assert!(changed.unwrap().is_ok());
+    }
+}